@@ -1,16 +1,24 @@
-use chrono::offset::Utc;
 use mongodb::bson::{doc, document::Document};
-//use mongodb::{options::ClientOptions, options::FindOptions, Client, Collection};
-use mongodb::{options::ClientOptions, options::FindOneOptions, options::FindOptions, options::InsertManyOptions, options::ReadConcern, Client, Cursor};
+//use mongodb::{options::ClientOptions, options::FindOneOptions, options::FindOptions, options::ReplaceOptions, Client, Collection};
+use mongodb::{options::ClientOptions, options::FindOneOptions, options::FindOptions, options::ReplaceOptions, options::InsertManyOptions, options::ReadConcern, Client, Cursor, Database};
 //use serde::{Deserialize, Serialize};
 use futures::StreamExt;
 use clap::ArgMatches;
 use std::error;
 use std::mem;
+use std::time::{Duration, Instant};
 //use tokio::task;
-use bson::oid::ObjectId;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::sync::Semaphore;
+use crate::filter::{Mapping, PerNamespace};
+use crate::dead_letter::DeadLetters;
+use crate::limit::Limiter;
+use crate::metrics;
+use crate::progress::Progress;
+use crate::state::Checkpoint;
+use mongodb::error::{ErrorKind, WriteFailure};
+use bson::Bson;
+use rand::Rng;
 
 #[derive(Clone, Debug)]
 pub struct DB {
@@ -19,8 +27,55 @@ pub struct DB {
     pub renamedb: Option<String>
 }
 
+// Source namespaces, classified by type
+#[derive(Clone, Debug, Default)]
+pub struct Namespaces {
+    pub collections: Vec<String>,
+    pub views: Vec<Document>,
+    pub system: Vec<String>
+}
+
 type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
 
+// Sampled _ids per range when splitting a collection
+const SAMPLES_PER_PARTITION: usize = 20;
+
+// Server error codes for requests or documents over the size limit
+const TOO_LARGE_CODES: [i32; 2] = [
+    10334,  // BSONObjectTooLarge
+    17419,  // Total size of documents exceeds the maximum
+];
+
+// Largest batch of upserts sent in one update command
+const UPDATE_COMMAND_BYTES: usize = 15 * 1024 * 1024;
+
+// Server error codes after which a write can be tried again, as listed in the driver retryable writes spec,
+// along with 16500 which throttled servers return for too many requests
+const RETRYABLE_WRITE_CODES: [i32; 13] = [
+    6, 7, 89, 91, 189, 262, 9001, 10107, 11600, 11602, 13435, 13436, 16500
+];
+
+// First and longest wait between write retries
+const RETRY_BASE: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(10);
+
+// Groups of $type aliases in the order the server sorts BSON values, where types within a group compare to each other
+const BSON_TYPE_ORDER: [&[&str]; 13] = [
+    &["minKey"],
+    &["null", "undefined"],
+    &["number"],
+    &["string", "symbol"],
+    &["object"],
+    &["array"],
+    &["binData"],
+    &["objectId"],
+    &["bool"],
+    &["date"],
+    &["timestamp"],
+    &["regex"],
+    &["maxKey"],
+];
+
 impl DB {
     pub async fn init(url: &str, db: &str, renamedb: Option<&str>) -> BoxResult<Self> {
         let mut client_options = ClientOptions::parse(url).await?;
@@ -44,11 +99,88 @@ impl DB {
         })
     }
 
-    pub async fn collections(&self) -> BoxResult<Vec<String>> {
-        Ok(self.client.database(&self.db).list_collection_names(None).await?)
+    // Check whether two URIs point at the same deployment, by their hosts or by the server process they reach
+    pub async fn same_deployment(&self, source_uri: &str, other: &DB, destination_uri: &str) -> BoxResult<bool> {
+        let source_hosts = ClientOptions::parse(source_uri).await?.hosts;
+        let destination_hosts = ClientOptions::parse(destination_uri).await?.hosts;
+        if source_hosts.iter().any(|h| destination_hosts.contains(h)) {
+            return Ok(true)
+        };
+
+        // serverStatus may not be allowed, in which case the hosts have to do
+        let identity = |status: Document| (status.get_str("host").map(String::from).ok(), status.get("pid").cloned());
+        let source = self.client.database("admin").run_command(doc!{ "serverStatus": 1 }, None).await;
+        let destination = other.client.database("admin").run_command(doc!{ "serverStatus": 1 }, None).await;
+        match (source, destination) {
+            (Ok(source), Ok(destination)) => {
+                let (source, destination) = (identity(source), identity(destination));
+                Ok(source.0.is_some() && source == destination)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    // Pick _id boundaries that split a collection into roughly even ranges, from a random sample
+    pub async fn boundaries(&self, collection: &str, partitions: usize) -> BoxResult<Vec<Bson>> {
+        let pipeline = vec![
+            doc!{ "$sample": { "size": (partitions * SAMPLES_PER_PARTITION) as i64 } },
+            doc!{ "$project": { "_id": 1 } },
+            doc!{ "$sort": { "_id": 1 } }
+        ];
+
+        let mut cursor = self.client.database(&self.db).collection(collection).aggregate(pipeline, None).await?;
+        let mut ids = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            ids.push(doc?.get("_id").cloned().unwrap_or(Bson::Null));
+        }
+
+        let mut boundaries: Vec<Bson> = (1..partitions)
+            .filter_map(|p| ids.get(p * ids.len() / partitions).cloned())
+            .collect();
+        boundaries.dedup();
+
+        log::debug!("{}.{}: Split at {} _id boundaries", self.db, collection, boundaries.len());
+        Ok(boundaries)
+    }
+
+    // Get a handle on another database, sharing the same client
+    pub fn database(&self, db: &str, renamedb: Option<&str>) -> Self {
+        Self {
+            client: self.client.clone(),
+            db: db.to_owned(),
+            renamedb: renamedb.map(|s| s.into())
+        }
+    }
+
+    pub async fn databases(&self) -> BoxResult<Vec<String>> {
+        Ok(self.client.list_database_names(None, None).await?)
+    }
+
+    pub async fn namespaces(&self) -> BoxResult<Namespaces> {
+        let mut namespaces = Namespaces::default();
+        let mut cursor = self.client.database(&self.db).list_collections(None, None).await?;
+
+        while let Some(info) = cursor.next().await {
+            let info = info?;
+            let name = info.get_str("name")?.to_string();
+
+            // system.views, system.profile, system.buckets.* and friends are managed by the server
+            if name.starts_with("system.") {
+                namespaces.system.push(name);
+                continue;
+            };
+
+            // Time-series collections are read and written like regular collections
+            match info.get_str("type").unwrap_or("collection") {
+                "view" => namespaces.views.push(info),
+                _ => namespaces.collections.push(name)
+            };
+        }
+
+        Ok(namespaces)
     }
 
-    pub async fn newest(&mut self, collection: &str) -> Option<String> {
+    pub async fn newest(&mut self, collection: &str) -> Option<Bson> {
 
         // Get destination db name
         let db = match &self.renamedb {
@@ -60,7 +192,7 @@ impl DB {
         log::info!("{}.{}: Getting newest doc in destination", db, collection);
 
         // Get handle on collection
-        let collection_handle = self.client.database(&db).collection(collection);
+        let collection_handle = self.client.database(db).collection(collection);
 
         let options = mongodb::options::FindOneOptions::builder().sort(doc! { "_id": -1 }).projection(doc!{"_id": 1}).build();
 
@@ -69,7 +201,7 @@ impl DB {
             Ok(result) => {
                 match result {
                     Some(doc) => {
-                        let id = doc.get_object_id("_id").ok()?.to_string();
+                        let id = doc.get("_id")?.clone();
                         log::info!("{}.{}: Found newest doc with id: {}", db, collection, &id);
                         Some(id)
                     },
@@ -86,9 +218,9 @@ impl DB {
         }
     }
 
-    pub async fn find(&mut self, collection: &str, bulk_size: Option<u64>, newest: Option<String>) -> BoxResult<(Cursor, Counter)> {
+    pub async fn find(&mut self, collection: &str, bulk_size: Option<u64>, newest: Option<Bson>, filter: Document, projection: Option<Document>) -> BoxResult<(Cursor, Counter)> {
         // Create counter
-        let mut counter = Counter::new();
+        let counter = Counter::new();
 
         // Log which collection this is going into
         log::debug!("Reading {}.{}", self.db, collection);
@@ -108,24 +240,32 @@ impl DB {
         let find_options = FindOptions::builder()
             .batch_size(batch_size)
             .sort(doc! { "_id": 1 })
+            .projection(projection)
             .build();
 
         // Get handle on collection
         let collection_handle = self.client.database(&self.db).collection(collection);
 
         // If --continue is set, find the oldest doc, and start there
-        let query = match newest {
-            Some(ref id) => doc!{ "_id": {"$gt": ObjectId::with_string(id)? } },
+        let marker = match newest {
+            Some(id) => bound(id, "$gt"),
             None => doc!{}
         };
 
+        // Combine the marker with any user supplied filter
+        let query = match (marker.is_empty(), filter.is_empty()) {
+            (_, true) => marker,
+            (true, false) => filter,
+            (false, false) => doc!{ "$and": [ filter, marker ] }
+        };
+
         // Set total in Counter
-        let total = match newest {
-            Some(_) => {
-                log::info!("{}.{}: Calculating docs past marker", self.db, collection);
+        let total = match !query.is_empty() {
+            true => {
+                log::info!("{}.{}: Calculating docs matching query", self.db, collection);
                 collection_handle.count_documents(query.clone(), None).await? as f64
             },
-            None => {
+            false => {
                 log::info!("{}.{}: Counting all docs in collection", self.db, collection);
                 self.count(collection).await?
             }
@@ -139,33 +279,46 @@ impl DB {
         Ok((cursor, counter))
     }
 
-    pub async fn insert_cursor(&mut self, collection: &str, mut cursor: Cursor, mut counter: Counter) -> BoxResult<()> {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert_cursor(&mut self, collection: &str, mut cursor: Cursor, counter: Counter, mode: WriteMode, retries: u32, checkpoint: Checkpoint, limiter: Limiter, dead_letters: DeadLetters) -> BoxResult<Written> {
         // Get destination db name
         let db = match &self.renamedb {
             Some(db) => db,
             None => &self.db
         };
 
-        // Get handle on collection
-        let collection_handle = self.client.database(&db).collection(collection);
+        // Get handle on db
+        let database = self.client.database(db);
 
-        log::info!("{}.{}: Inserting {} docs", db, collection, counter.total);
+        log::info!("{}.{}: Inserting {} docs", db, collection, counter.total());
 
         // Get timestamp
-        let start = Utc::now().timestamp();
+        let start = Instant::now();
+
+        // Each doc is checkpointed as its own batch
+        let mut batch: u64 = 0;
+        let mut written = Written::default();
         
         while let Some(doc) = cursor.next().await {
             match doc {
                 Ok(doc) => {
-                    match collection_handle.insert_one(doc, None).await {
-                        Ok(id) => {
-                            log::debug!("{}.{}: Inserted id: {}", db, collection, id.inserted_id.to_string());
-                        }
-                        Err(e) => {
-                            log::debug!("{}.{}: Got error: {}", db, collection, e);
-                        }
-                    }
-                    counter.incr(&db, collection, 1.0, start);
+                    metrics::add(metrics::DOCS_READ, &[("namespace", &format!("{}.{}", db, collection))], 1.0);
+                    let id = doc.get("_id").cloned();
+                    let result = write_batch(&database, collection, vec![doc], mode, false, false, retries, &limiter, &dead_letters).await;
+                    let ok = result.ok();
+                    written.add(result);
+                    if ok {
+                        log::debug!("{}.{}: Inserted id: {}", db, collection, id.clone().unwrap_or(Bson::Null));
+                    };
+                    checkpoint.written(batch, id, 1.0, ok);
+                    batch += 1;
+                    counter.incr(db, collection, 1.0, start);
+
+                    // Stop at the first conflict
+                    if written.conflicts > 0 {
+                        log::error!("{}.{}: Stopping at the first write conflict", db, collection);
+                        break;
+                    };
                 }
                 Err(e) => {
                     log::error!("{}.{}: Caught error getting next doc: {}", db, collection, e);
@@ -173,91 +326,46 @@ impl DB {
                 }
             };
         }
-        log::info!("{}.{}: Injected {} docs", db, collection, counter.count());
+        log::info!(db = db.as_str(), collection, phase = "copy", count = counter.count() as u64, duplicates = written.duplicates, failed = written.failed;
+            "{}.{}: Injected {} docs, skipped {} duplicates, {} failed", db, collection, counter.count(), written.duplicates, written.failed);
         log::info!("{}.{}: Closing cursor", db, collection);
-        Ok(())
+        Ok(written)
     }
 
-    pub async fn validate_docs(&mut self, collection: &str, mut cursor: Cursor, mut counter: Counter) -> BoxResult<()> {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn bulk_insert_cursor(&mut self, collection: &str, mut cursor: Cursor, counter: Counter, bulk_count: usize, bulk_bytes: usize, continue_upload: bool, verbose: bool, mode: WriteMode, retries: u32, checkpoint: Checkpoint, limiter: Limiter, dead_letters: DeadLetters) -> BoxResult<Written> {
         // Get destination db name
         let db = match &self.renamedb {
             Some(db) => db,
             None => &self.db
         };
 
-        // Get handle on collection
-        let collection_handle = self.client.database(&db).collection(collection);
-
-        log::info!("{}.{}: Validating that {} docs in destination exist in source", db, collection, counter.total);
-
-        // Get timestamp
-        let start = Utc::now().timestamp();
-
-        let find_one_options = FindOneOptions::builder()
-            .projection(doc! { "_id": 1 })
-            .build();
+        // Get handle on db
+        let database = self.client.database(db);
 
-        while let Some(doc) = cursor.next().await {
-            match doc {
-                Ok(doc) => {
-                    let id = doc.get_object_id("_id")?;
-                    match collection_handle.find_one(doc!{ "_id": id }, find_one_options.clone()).await {
-                        Ok(_id) => {
-                            log::debug!("{}.{}: Found {} in source collection", db, collection, id);
-                        }
-                        Err(e) => {
-                            log::error!("{}.{}: Got error finding {}: {}", db, collection, id, e);
-                        }
-                    }
-                    counter.incr(&db, collection, 1.0, start);
-                }
-                Err(e) => {
-                    log::error!("{}.{}: Caught error getting next doc: {}", db, collection, e);
-                    continue;
-                }
-            };
-        }
-        log::info!("{}.{}: Completed validation, closing cursor", db, collection);
-        Ok(())
-    }
-
-    pub async fn bulk_insert_cursor(&mut self, collection: &str, mut cursor: Cursor, mut counter: Counter, bulk_count: usize, continue_upload: bool, verbose: bool) -> BoxResult<()> {
-        // Get destination db name
-        let db = match &self.renamedb {
-            Some(db) => db,
-            None => &self.db
-        };
-
-        // Get handle on collection
-        let collection_handle = self.client.database(&db).collection(collection);
-
-        if counter.total != 0.0 {
-            log::info!("{}.{}: Bulk inserting {} docs in batches of {}", db, collection, counter.total, bulk_count);
+        if counter.total() != 0.0 {
+            log::info!("{}.{}: Bulk inserting {} docs in batches of {}", db, collection, counter.total(), bulk_count);
         } else {
-            log::info!("{}.{}: There are {} docs to upload", db, collection, counter.total);
+            log::info!("{}.{}: There are {} docs to upload", db, collection, counter.total());
         };
 
-        let insert_many_options = match continue_upload {
-            true => {
-                InsertManyOptions::builder()
-                    .ordered(Some(true))        // This should stay true, so that --continue is able to properly start where the last operation left off
-                    .build()
-            },
-            false=> {
-                InsertManyOptions::builder()
-                    .ordered(Some(false))        // If --continue is not set, go ahead and use unordered inserts
-                    .build()
-            }
-        };
+        // This should stay true with --continue, so that it is able to properly start where the last operation left off.
+        // Fail mode also writes in order, so that nothing after the first conflict is written.
+        // Otherwise, go ahead and use unordered writes
+        let ordered = continue_upload || mode == WriteMode::Fail;
 
         // Create vector of documents to bulk upload
         let mut bulk: Vec<Document> = Vec::with_capacity(bulk_count);
 
         // Get timestamp
-        let start = Utc::now().timestamp();
+        let start = Instant::now();
         
-        // Set count
+        // Set count, and encoded size of the docs in the batch
         let mut count: usize = 0;
+        let mut bytes: usize = 0;
+
+        // Number batches in cursor order, so that checkpoints only move past fully written batches
+        let mut batch: u64 = 0;
 
 // DEBUG
 //        let mut bulk_uploads = 0;
@@ -265,6 +373,7 @@ impl DB {
 
         // Create vector for task handles
         let mut handles = vec![];
+        let mut written = Written::default();
 
         // Let's rate limit to just 4 uploads at once
         let sem = Arc::new(Semaphore::new(4));
@@ -274,42 +383,66 @@ impl DB {
             match doc {
                 Ok(d) => {
                     // Push to vec, incr counter, and print debug log
+                    let size = doc_size(&d);
                     bulk.push(d);
                     count += 1;
+                    bytes += size;
                     log::debug!("{}.{}: inserted doc: {}/{}", db, collection, count, bulk_count);
 
-                    // If counter is greater or equal to bulk_count
-                    if count >= bulk_count {
+                    // If counter is greater or equal to bulk_count, or the batch went over its byte budget
+                    let over_budget = bytes > bulk_bytes && bulk.len() > 1;
+                    if count >= bulk_count || over_budget {
+
+                        // Hold back the doc that took the batch over its byte budget, it starts the next batch
+                        let carry = match over_budget {
+                            true => bulk.pop(),
+                            false => None
+                        };
 
                         // Create a new empty vec, then swap, to avoid clone()
                         let mut tmp_bulk: Vec<Document> = Vec::with_capacity(bulk_count);
                         mem::swap(&mut bulk, &mut tmp_bulk);
+                        let batch_len = tmp_bulk.len();
                     
                         // Get clones for the threads
-                        let coll_clone = collection_handle.clone();
-                        let options = insert_many_options.clone();
-
-                        // Get permission to kick off task
-                        let permit = Arc::clone(&sem).acquire_owned().await;
-
-                        handles.push(tokio::spawn(async move {
-                            let _permit = permit;
-                            match coll_clone.insert_many(tmp_bulk, options.clone()).await {
-                                Ok(_) => {
-                                    log::debug!("Bulk inserted {} docs", bulk_count);
-                                }
-                                Err(e) => {
-                                    if verbose {
-                                        log::error!("Got error with insertMany: {}", e);
-                                    } else {
-                                        log::debug!("Got error with insertMany: {}", e);
-                                    }
-                                }
+                        let database = database.clone();
+                        let coll_name = collection.to_string();
+                        let batch_checkpoint = checkpoint.clone();
+                        let limiter = limiter.clone();
+                        let dead_letters = dead_letters.clone();
+                        let last_id = tmp_bulk.last().and_then(|d| d.get("_id").cloned());
+                        let this_batch = batch;
+                        batch += 1;
+
+                        // Fail mode writes each batch before reading the next, so that it can stop right at a conflict
+                        match mode {
+                            WriteMode::Fail => {
+                                let result = write_batch(&database, &coll_name, tmp_bulk, mode, ordered, verbose, retries, &limiter, &dead_letters).await;
+                                if result.ok() {
+                                    log::debug!("Bulk inserted {} docs", batch_len);
+                                };
+                                batch_checkpoint.written(this_batch, last_id, batch_len as f64, result.ok());
+                                written.add(result);
+                            },
+                            _ => {
+                                // Get permission to kick off task
+                                let permit = Arc::clone(&sem).acquire_owned().await;
+
+                                handles.push(tokio::spawn(async move {
+                                    let _permit = permit;
+                                    let written = write_batch(&database, &coll_name, tmp_bulk, mode, ordered, verbose, retries, &limiter, &dead_letters).await;
+                                    if written.ok() {
+                                        log::debug!("Bulk inserted {} docs", batch_len);
+                                    };
+                                    batch_checkpoint.written(this_batch, last_id, batch_len as f64, written.ok());
+                                    written
+                                }));
                             }
-                        }));
+                        };
 //                            };
 
-                        counter.incr(&db, collection, count as f64, start);
+                        counter.incr(db, collection, batch_len as f64, start);
+                        metrics::add(metrics::DOCS_READ, &[("namespace", &format!("{}.{}", db, collection))], batch_len as f64);
 
                         // DEBUG
  //                       let current_total = self.count(collection).await.expect("expect failed");
@@ -320,6 +453,18 @@ impl DB {
                         // END DEBUG
 
                         count = 0;
+                        bytes = 0;
+                        if let Some(doc) = carry {
+                            bulk.push(doc);
+                            count = 1;
+                            bytes = size;
+                        };
+
+                        // Stop at the first conflict
+                        if written.conflicts > 0 {
+                            log::error!("{}.{}: Stopping at the first write conflict", db, collection);
+                            break;
+                        };
                     } else {
                         continue
                     }
@@ -337,29 +482,43 @@ impl DB {
 
         // Push any remaining docs to destination
         let bulk_len = &bulk.len();
-        if bulk_len > &0 {
-            match collection_handle.insert_many(bulk, insert_many_options).await {
-                Ok(_) => {
-                    log::debug!("Bulk inserted {} docs", bulk_len);
-                }
-                Err(e) => {
-                    if verbose {
-                        log::error!("Got error with insertMany: {}", e);
-                    } else {
-                        log::debug!("Got error with insertMany: {}", e);
-                    }
-                }
+        if bulk_len > &0 && written.conflicts == 0 {
+            let last_id = bulk.last().and_then(|d| d.get("_id").cloned());
+            let result = write_batch(&database, collection, bulk, mode, ordered, verbose, retries, &limiter, &dead_letters).await;
+            if result.ok() {
+                log::debug!("Bulk inserted {} docs", bulk_len);
             };
-            counter.incr(&db, collection, *bulk_len as f64, start);
+            checkpoint.written(batch, last_id, *bulk_len as f64, result.ok());
+            written.add(result);
+            counter.incr(db, collection, *bulk_len as f64, start);
+            metrics::add(metrics::DOCS_READ, &[("namespace", &format!("{}.{}", db, collection))], *bulk_len as f64);
         };
 
         // Wait for all handles to complete
         log::info!("{}.{}: Waiting for all threads to close", db, collection);
-        futures::future::join_all(handles).await;
-        log::info!("{}.{}: Injected {} docs", db, collection, counter.count());
+        for result in futures::future::join_all(handles).await {
+            match result {
+                Ok(result) => written.add(result),
+                Err(e) => return Err(e.into())
+            };
+        }
+        log::info!(db = db.as_str(), collection, phase = "copy", count = counter.count() as u64, duplicates = written.duplicates, failed = written.failed;
+            "{}.{}: Injected {} docs, skipped {} duplicates, {} failed", db, collection, counter.count(), written.duplicates, written.failed);
 
         log::info!("{}.{}: Closing cursor", db, collection);
-        Ok(())
+        Ok(written)
+    }
+
+    // Doc count and uncompressed data size of a collection, as estimated by the server
+    pub async fn stats(&self, collection: &str) -> BoxResult<(f64, f64)> {
+        let response = self.client.database(&self.db).run_command(doc!{ "collStats": collection }, None).await?;
+        let number = |key: &str| match response.get(key) {
+            Some(Bson::Int32(i)) => *i as f64,
+            Some(Bson::Int64(i)) => *i as f64,
+            Some(Bson::Double(f)) => *f,
+            _ => 0.0
+        };
+        Ok((number("count"), number("size")))
     }
 
     #[allow(dead_code)]
@@ -381,8 +540,7 @@ impl DB {
         }
     }
 
-    #[allow(dead_code)]
-    pub async fn get_indexes(&self, collection: &str) -> BoxResult<Document> {
+    pub async fn get_indexes(&self, collection: &str) -> BoxResult<Vec<Document>> {
         // Log that we are trying to list collections
         log::debug!("Getting indexes in {}", self.db);
 
@@ -393,7 +551,11 @@ impl DB {
             Ok(indexes) => {
                 log::debug!("Successfully got indexes in {}.{}", self.db, collection);
                 let index_cursor = indexes.get_document("cursor").expect("Successfully got indexes, but failed to extract cursor").clone();
-                Ok(index_cursor)
+                let indexes = index_cursor.get_array("firstBatch")?
+                    .iter()
+                    .filter_map(|index| index.as_document().cloned())
+                    .collect();
+                Ok(indexes)
             }
             Err(e) => {
                 log::error!("Got error {}", e);
@@ -401,106 +563,991 @@ impl DB {
             }
         }
     }
+
+    pub async fn collection_info(&self, collection: &str) -> BoxResult<Option<Document>> {
+        // Get destination db name
+        let db = match &self.renamedb {
+            Some(db) => db,
+            None => &self.db
+        };
+
+        let mut cursor = self.client.database(db).list_collections(doc!{ "name": collection }, None).await?;
+
+        match cursor.next().await {
+            Some(info) => Ok(Some(info?)),
+            None => Ok(None)
+        }
+    }
+
+    pub async fn drop_collection(&self, collection: &str) -> BoxResult<()> {
+        // Get destination db name
+        let db = match &self.renamedb {
+            Some(db) => db,
+            None => &self.db
+        };
+
+        log::info!("{}.{}: Dropping destination collection", db, collection);
+        self.client.database(db).collection(collection).drop(None).await?;
+        Ok(())
+    }
+
+    pub async fn create_collection(&self, collection: &str, mut options: Document) -> BoxResult<()> {
+        // Get destination db name
+        let db = match &self.renamedb {
+            Some(db) => db,
+            None => &self.db
+        };
+
+        // If the collection already exists, keep it, but warn when its options differ
+        if let Some(existing) = self.collection_info(collection).await? {
+            let existing_options = existing.get_document("options").cloned().unwrap_or_default();
+            if existing_options != options {
+                log::warn!("{}.{}: Collection already exists with different options: {}", db, collection, existing_options);
+            } else {
+                log::debug!("{}.{}: Collection already exists", db, collection);
+            };
+            return Ok(())
+        };
+
+        // The server sets the index version itself
+        if let Ok(clustered) = options.get_document_mut("clusteredIndex") {
+            clustered.remove("v");
+        };
+
+        log::info!("{}.{}: Creating collection with options: {}", db, collection, options);
+
+        let mut command = doc! { "create": collection };
+        command.extend(options);
+
+        match self.client.database(db).run_command(command, None).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // Fail rather than silently downgrade to a plain collection
+                log::error!("{}.{}: Destination could not create collection with source options: {}", db, collection, e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn create_indexes(&self, collection: &str, indexes: Vec<Document>) -> BoxResult<()> {
+        // Get destination db name
+        let db = match &self.renamedb {
+            Some(db) => db,
+            None => &self.db
+        };
+
+        // The _id index is always created with the collection, and ns/v are set by the server
+        let specs: Vec<Document> = indexes.into_iter()
+            .filter(|index| index.get_str("name") != Ok("_id_"))
+            .map(|mut index| {
+                index.remove("ns");
+                index.remove("v");
+                index
+            })
+            .collect();
+
+        if specs.is_empty() {
+            log::debug!("{}.{}: No indexes to create", db, collection);
+            return Ok(())
+        };
+
+        log::info!("{}.{}: Creating {} indexes", db, collection, specs.len());
+
+        let command = doc! { "createIndexes": collection, "indexes": specs };
+
+        match self.client.database(db).run_command(command, None).await {
+            Ok(_) => {
+                log::info!("{}.{}: Successfully created indexes", db, collection);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("{}.{}: Got error creating indexes: {}", db, collection, e);
+                Err(Box::new(e))
+            }
+        }
+    }
 }
 
-#[derive(Clone, Copy,  Debug)]
+// Progress of a collection, shared by every cursor copying part of it
+#[derive(Clone, Debug)]
 pub struct Counter {
-    pub count: f64,
-    pub marker: f64,
-    pub total: f64
+    tally: Arc<Mutex<Tally>>,
+    progress: Option<(Progress, String)>
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Tally {
+    count: f64,     // Count of docs uploaded
+    marker: f64,    // Percentage tracker
+    total: f64,     // Total count of all docs in collection
+    phase: &'static str
 }
 
 impl Counter {
     pub fn new() -> Counter {
         Counter {
-            count: 0.0,     // Count of docs uploaded
-            marker: 0.0,    // Percentage tracker
-            total: 0.0,     // Total count of all docs in collection
+            tally: Arc::new(Mutex::new(Tally { phase: "copy", ..Tally::default() })),
+            progress: None
         }
     }
 
+    // Feed every doc counted into the progress of the whole job
+    pub fn attach(&mut self, progress: Progress, namespace: String) {
+        self.progress = Some((progress, namespace));
+    }
+
+    // What the counted docs are being read for, logged with the progress
+    pub fn set_phase(&self, phase: &'static str) {
+        self.tally.lock().unwrap().phase = phase;
+    }
+
 //    pub fn set(&mut self, count: i64) {
 //        self.count = count as f64;
 //    }
 
-    pub fn set_total(&mut self, total: f64) {
-        self.total = total;
+    pub fn set_total(&self, total: f64) {
+        self.tally.lock().unwrap().total = total;
     }
 
     pub fn count(&self) -> f64 {
-        self.count
+        self.tally.lock().unwrap().count
     }
 
     pub fn total(&self) -> f64 {
-        self.total
+        self.tally.lock().unwrap().total
     }
 
-    pub fn incr(&mut self, db: &str, collection: &str, count: f64, start: i64) {
-        self.count += count;
-        let percent = self.count / self.total * 100.0;
+    pub fn incr(&self, db: &str, collection: &str, count: f64, start: Instant) {
+        if let Some((progress, namespace)) = &self.progress {
+            progress.advance(namespace, count);
+        };
+
+        let mut tally = self.tally.lock().unwrap();
+        tally.count += count;
 
-        // Get time elapsed
-        let now = Utc::now().timestamp();
-        let delta = now - start;
+        // A collection with nothing to copy is already done
+        let percent = match tally.total > 0.0 {
+            true => tally.count / tally.total * 100.0,
+            false => 100.0
+        };
 
-        // Get insert rate
-        let rate = self.count / delta as f64;
+        // Get insert rate, averaged over at least a second so that the first batches do not divide by a zero length interval
+        let rate = tally.count / start.elapsed().as_secs_f64().max(1.0);
 
-        if self.count == self.total {
-            log::info!("{}.{}: 100%, {:.2}/s, {}/{}", db, collection, rate, self.count, self.total);
-        } else if percent - self.marker > 1.0 {
-            if self.count > self.total {
-                log::info!("{}.{}: (catching up) {:.2}%, {:.2}/s, {}/{}", db, collection, percent, rate, self.count, self.total);
+        if tally.count == tally.total {
+            log::info!(db, collection, phase = tally.phase, count = tally.count as u64, total = tally.total as u64, percent = 100.0, rate;
+                "{}.{}: 100%, {:.2}/s, {}/{}", db, collection, rate, tally.count, tally.total);
+        } else if percent - tally.marker > 1.0 {
+            if tally.count > tally.total {
+                log::info!(db, collection, phase = tally.phase, count = tally.count as u64, total = tally.total as u64, percent, rate;
+                    "{}.{}: (catching up) {:.2}%, {:.2}/s, {}/{}", db, collection, percent, rate, tally.count, tally.total);
             } else {
-                log::info!("{}.{}: {:.2}%, {:.2}/s, {}/{}", db, collection, percent, rate, self.count, self.total);
+                log::info!(db, collection, phase = tally.phase, count = tally.count as u64, total = tally.total as u64, percent, rate;
+                    "{}.{}: {:.2}%, {:.2}/s, {}/{}", db, collection, percent, rate, tally.count, tally.total);
             }
-            self.marker += 1f64;
+            tally.marker += 1f64;
         };
     }
 }
 
-pub async fn transfer(mut source_db: DB, mut destination_db: DB, opts: ArgMatches<'_>, source_collection: String, rename_coll: Option<String>) -> BoxResult<()> {
+#[allow(clippy::too_many_arguments)]
+pub async fn transfer(mut source_db: DB, mut destination_db: DB, opts: ArgMatches<'_>, source_collection: String, rename_coll: Option<String>, checkpoint: Checkpoint, limiter: Limiter, dead_letters: DeadLetters, progress: Progress) -> BoxResult<(u64, Written)> {
 
     let bulk_size = match opts.is_present("bulk") {
         true => opts.value_of("bulk").unwrap().parse::<u32>()?,
         false => 2000u32
     };
 
+    let bulk_bytes = opts.value_of("bulk_bytes").unwrap_or("16777216").parse::<usize>()?;
+
+    let mode = WriteMode::new(opts.value_of("write_mode").unwrap_or("insert"));
+
+    let retries = opts.value_of("retries").unwrap_or("5").parse::<u32>()?;
+
     // If renamecoll is Some
     let destination_collection = match rename_coll {
         Some(c) => c,
         None => source_collection.clone()
     };
     
-    // If --continue is set, find newest doc
-    let newest_doc = match opts.is_present("continue") {
-        true => destination_db.newest(&destination_collection).await,
-        false => None
-    };
-
-    // If bulk flag is set, use insertMany
-    match opts.is_present("nobulk") {
-        false => {
-            // Acquire cursor from source
-            let (source_cursor,counter) = source_db.find(&source_collection, Some(bulk_size as u64), newest_doc).await?;
-            destination_db.bulk_insert_cursor(&destination_collection, source_cursor, counter, bulk_size as usize, opts.is_present("continue"), opts.is_present("verbose")).await?;
+    // With --drop, start from an empty collection, which is then recreated with the source options and indexes
+    if opts.is_present("drop") {
+        destination_db.drop_collection(&destination_collection).await?;
+    };
+
+    // Explicitly create the collection with the source options, so that capped, validators, collation and time-series settings are kept
+    let source_info = source_db.collection_info(&source_collection).await?;
+    let collection_options = source_info
+        .and_then(|info| info.get_document("options").ok().cloned())
+        .unwrap_or_default();
+    destination_db.create_collection(&destination_collection, collection_options).await?;
+
+    // Read index specs from source, so that they can be built before or after the load
+    let index_build = opts.value_of("indexes").unwrap_or("after");
+    let indexes = match index_build {
+        "none" => Vec::new(),
+        _ => source_db.get_indexes(&source_collection).await?
+    };
+
+    if index_build == "before" {
+        destination_db.create_indexes(&destination_collection, indexes.clone()).await?;
+    };
+
+    // Only copy docs matching the user supplied query, if any
+    let query = PerNamespace::new(opts.values_of("query").into_iter().flatten().collect())?.get(&source_db.db, &source_collection);
+    if !query.is_empty() {
+        log::info!("{}.{}: Copying docs matching {}", source_db.db, source_collection, query);
+    };
+
+    // Only copy the fields selected by the user supplied projection, if any
+    let projection = projection(&opts, &source_db.db, &source_collection)?;
+
+    let resume = opts.is_present("resume");
+
+    // Split large collections into _id ranges, reusing the boundaries of an interrupted run when resuming
+    let partitions = opts.value_of("partitions").unwrap_or("1").parse::<usize>()?;
+    let boundaries = match (partitions > 1, checkpoint.boundaries()) {
+        (false, _) => Vec::new(),
+        (true, Some(boundaries)) if resume => boundaries,
+        (true, _) => {
+            let boundaries = source_db.boundaries(&source_collection, partitions).await?;
+            checkpoint.set_boundaries(boundaries.clone());
+            boundaries
         }
-        true => {
-            // Acquire cursor from source
-            let (source_cursor,counter) = source_db.find(&source_collection, None, newest_doc).await?;
-            destination_db.insert_cursor(&destination_collection, source_cursor, counter).await?
-        }   
     };
 
-    Ok(())
+    // Each range is copied by its own cursor, with its own checkpoint
+    let mut ranges: Vec<(Checkpoint, Document, Option<Bson>)> = Vec::new();
+    if boundaries.is_empty() {
+        // If --resume is set, start right after the last confirmed _id, otherwise if --continue is set, find newest doc
+        let newest_doc = match (resume, opts.is_present("continue")) {
+            (true, _) => checkpoint.resume(),
+            (false, true) => destination_db.newest(&destination_collection).await,
+            (false, false) => None
+        };
+        ranges.push((checkpoint.clone(), query, newest_doc));
+    } else {
+        log::info!("{}.{}: Copying {} _id ranges concurrently", source_db.db, source_collection, boundaries.len() + 1);
+        for partition in 0..=boundaries.len() {
+            let mut filter = Vec::new();
+            if !query.is_empty() {
+                filter.push(Bson::Document(query.clone()));
+            };
+            if partition > 0 {
+                filter.push(Bson::Document(bound(boundaries[partition - 1].clone(), "$gte")));
+            };
+            if partition < boundaries.len() {
+                filter.push(Bson::Document(bound(boundaries[partition].clone(), "$lt")));
+            };
+
+            let part = checkpoint.partition(partition);
+            let marker = match resume {
+                true => part.resume(),
+                false => None
+            };
+            ranges.push((part, doc!{ "$and": filter }, marker));
+        };
+    };
+
+    checkpoint.set_status("copying");
+
+    // Every range feeds into the same progress counter
+    let mut counter = Counter::new();
+    let batch_size = match opts.is_present("nobulk") {
+        false => Some(bulk_size as u64),
+        true => None
+    };
+
+    let mut cursors = Vec::new();
+    for (part, filter, marker) in ranges {
+        if let Some(id) = &marker {
+            log::info!("{}.{}: Resuming after checkpointed id: {}", source_db.db, source_collection, id);
+        };
+        let (source_cursor, part_counter) = source_db.find(&source_collection, batch_size, marker, filter, projection.clone()).await?;
+        counter.set_total(counter.total() + part_counter.total());
+        cursors.push((part, source_cursor));
+    };
+
+    // The job progress started from an estimate, now the number of docs to copy is known
+    let namespace = format!("{}.{}", source_db.db, source_collection);
+    progress.set_docs(&namespace, counter.total());
+    counter.attach(progress, namespace);
+
+    let parts: Vec<Checkpoint> = cursors.iter().map(|(part, _)| part.clone()).collect();
+    let mut handles = Vec::new();
+    for (part, source_cursor) in cursors {
+        let mut destination_db = destination_db.clone();
+        let destination_collection = destination_collection.clone();
+        let counter = counter.clone();
+        let limiter = limiter.clone();
+        let dead_letters = dead_letters.clone();
+        let (nobulk, continue_upload, verbose) = (opts.is_present("nobulk"), opts.is_present("continue"), opts.is_present("verbose"));
+
+        handles.push(tokio::spawn(async move {
+            // If bulk flag is set, use insertMany
+            match nobulk {
+                false => destination_db.bulk_insert_cursor(&destination_collection, source_cursor, counter, bulk_size as usize, bulk_bytes, continue_upload, verbose, mode, retries, part, limiter, dead_letters).await,
+                true => destination_db.insert_cursor(&destination_collection, source_cursor, counter, mode, retries, part, limiter, dead_letters).await
+            }
+        }));
+    };
+
+    // Wait for every range, and mark the collection failed if any range failed to be written
+    let mut failed = false;
+    let mut written = Written::default();
+    for result in futures::future::join_all(handles).await {
+        match result {
+            Ok(Ok(result)) => written.add(result),
+            Ok(Err(e)) => {
+                log::error!("{}.{}: Error copying range: {}", source_db.db, source_collection, e);
+                failed = true;
+            },
+            Err(e) => {
+                log::error!("{}.{}: Error joining range: {}", source_db.db, source_collection, e);
+                failed = true;
+            }
+        };
+    };
+
+    // Deferring index builds until after the load is usually faster
+    if index_build == "after" {
+        destination_db.create_indexes(&destination_collection, indexes).await?;
+    };
+
+    if failed {
+        return Err(format!("{}.{}: Some ranges could not be copied", source_db.db, source_collection).into())
+    };
+
+    // Keep the collection resumable if any docs were not written
+    if written.failed > 0 || parts.iter().any(|part| part.failed()) {
+        log::error!("{}.{}: {} docs could not be written", source_db.db, source_collection, written.failed);
+        checkpoint.fail(&format!("{} docs could not be written", written.failed));
+    } else {
+        checkpoint.set_status("done");
+    };
+
+    // Docs read from the source, and what became of them
+    Ok((counter.count() as u64, written))
+}
+
+// Match every doc whose _id compares to an _id with $gt, $gte or $lt. These operators only compare values of the
+// same type, so docs with an _id of a type that sorts later, or earlier for $lt, are matched by $type, following
+// the server's BSON sort order
+fn bound(id: Bson, operator: &str) -> Document {
+    let position = match type_order(&id) {
+        Some(position) => position,
+        None => return doc!{ "_id": { operator: id } }
+    };
+
+    let others = match operator {
+        "$lt" => &BSON_TYPE_ORDER[..position],
+        _ => &BSON_TYPE_ORDER[position + 1..]
+    };
+    let others: Vec<&str> = others.iter().flat_map(|types| types.iter().copied()).collect();
+    match others.is_empty() {
+        true => doc!{ "_id": { operator: id } },
+        false => doc!{ "$or": [ { "_id": { operator: id } }, { "_id": { "$type": others } } ] }
+    }
+}
+
+// Whether a value is or contains a Decimal128, which bson is built without extended json or string support for
+pub fn has_decimal128(value: &Bson) -> bool {
+    match value {
+        Bson::Decimal128(_) => true,
+        Bson::Document(doc) => doc.values().any(has_decimal128),
+        Bson::Array(values) => values.iter().any(has_decimal128),
+        _ => false
+    }
+}
+
+// Position of a value's type within BSON_TYPE_ORDER
+pub fn type_order(value: &Bson) -> Option<usize> {
+    match value {
+        Bson::MinKey => Some(0),
+        Bson::Null | Bson::Undefined => Some(1),
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => Some(2),
+        Bson::String(_) | Bson::Symbol(_) => Some(3),
+        Bson::Document(_) => Some(4),
+        Bson::Array(_) => Some(5),
+        Bson::Binary(_) => Some(6),
+        Bson::ObjectId(_) => Some(7),
+        Bson::Boolean(_) => Some(8),
+        Bson::DateTime(_) => Some(9),
+        Bson::Timestamp(_) => Some(10),
+        Bson::RegularExpression(_) => Some(11),
+        Bson::MaxKey => Some(12),
+        _ => None
+    }
+}
+
+// How docs are written when they may already exist at the destination
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteMode {
+    // Insert, skipping docs whose _id already exists
+    Insert,
+    // Replace docs by _id, inserting them if missing
+    Upsert,
+    // Insert, stopping the collection at the first conflict
+    Fail
+}
+
+impl WriteMode {
+    pub fn new(mode: &str) -> Self {
+        match mode {
+            "upsert" => WriteMode::Upsert,
+            "fail" => WriteMode::Fail,
+            _ => WriteMode::Insert
+        }
+    }
+}
+
+// Docs written, skipped as duplicates and that could not be written, out of a batch.
+// Conflicts are the failed docs whose _id already existed in fail mode
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Written {
+    pub written: u64,
+    pub duplicates: u64,
+    pub failed: u64,
+    pub conflicts: u64
+}
+
+impl Written {
+    // Whether every doc is at the destination
+    pub fn ok(&self) -> bool {
+        self.failed == 0
+    }
+
+    pub fn add(&mut self, other: Written) {
+        self.written += other.written;
+        self.duplicates += other.duplicates;
+        self.failed += other.failed;
+        self.conflicts += other.conflicts;
+    }
+}
+
+// Why an attempt to write a batch failed
+enum Failure {
+    // Per doc write errors as (index in the batch, code, message), ordered writes stop at the first one
+    Docs(Vec<(usize, i32, String)>),
+    // The batch as a whole failed, and whether it is worth retrying
+    Batch(Box<dyn error::Error + Send + Sync>, bool)
+}
+
+// Write a batch of docs to a destination collection, retrying transient errors, and only the docs that failed.
+// Docs that still fail are sent to the dead letters
+#[allow(clippy::too_many_arguments)]
+pub async fn write_batch(database: &Database, collection: &str, docs: Vec<Document>, mode: WriteMode, ordered: bool, verbose: bool, retries: u32, limiter: &Limiter, dead_letters: &DeadLetters) -> Written {
+    let db = database.name();
+    let namespace = format!("{}.{}", db, collection);
+    let labels = [("namespace", namespace.as_str())];
+    metrics::add(metrics::BATCHES_IN_FLIGHT, &labels, 1.0);
+
+    // Wait for the global rate limits
+    let bytes = match limiter.limits_bytes() {
+        true => docs.iter().map(doc_size).sum(),
+        false => 0
+    };
+    limiter.acquire(docs.len(), bytes).await;
+
+    // Batches left to write, with the number of retries already spent on them, written in order
+    let mut pending = vec![(docs, 0)];
+    let mut written = Written::default();
+    while let Some((docs, retried)) = pending.pop() {
+        let started = Instant::now();
+        let result = attempt(database, collection, &docs, mode, ordered).await;
+        metrics::observe(metrics::WRITE_SECONDS, &labels, started.elapsed());
+
+        match result {
+            Ok(_) => written.written += record_written(&labels, docs.iter()),
+            // Batches rejected for their size are split in half
+            Err(Failure::Batch(e, _)) if docs.len() > 1 && too_large(e.as_ref()) => {
+                record_error(&namespace, error_code(e.as_ref()));
+                log::warn!("{}.{}: Batch of {} docs is too large, splitting it: {}", db, collection, docs.len(), e);
+                let mut first = docs;
+                let second = first.split_off(first.len() / 2);
+                pending.push((second, retried));
+                pending.push((first, retried));
+            },
+            Err(Failure::Batch(e, true)) if retried < retries => {
+                record_error(&namespace, error_code(e.as_ref()));
+                let wait = backoff(retried);
+                log::warn!("{}.{}: Retrying batch of {} docs in {:?}, attempt {} of {}: {}", db, collection, docs.len(), wait, retried + 1, retries, e);
+                tokio::time::sleep(wait).await;
+                pending.push((docs, retried + 1));
+            },
+            Err(Failure::Batch(e, _)) => {
+                log_failure(db, collection, mode, verbose, &format!("Failed to write batch of {} docs: {}", docs.len(), e));
+                let code = error_code(e.as_ref());
+                record_error(&namespace, code);
+                for doc in &docs {
+                    dead_letters.write(db, collection, doc, code, &e.to_string());
+                }
+                written.failed += docs.len() as u64;
+            },
+            Err(Failure::Docs(errors)) => {
+                // Ordered writes stop at the first error, so the docs after it were never tried
+                let next = match ordered {
+                    true => errors.iter().map(|(index, _, _)| index + 1).max().unwrap_or(docs.len()).min(docs.len()),
+                    false => docs.len()
+                };
+                written.written += record_written(&labels, docs[..next].iter().enumerate().filter(|(i, _)| !errors.iter().any(|e| e.0 == *i)).map(|(_, doc)| doc));
+
+                let mut retry = Vec::new();
+                for (index, code, message) in &errors {
+                    record_error(&namespace, *code);
+                    let id = docs.get(*index).and_then(|d| d.get("_id")).cloned().unwrap_or(Bson::Null);
+                    if *code == 11000 && mode == WriteMode::Insert {
+                        // Already at the destination
+                        log::debug!("{}.{}: Skipped duplicate _id {}", db, collection, id);
+                        written.duplicates += 1;
+                    } else if RETRYABLE_WRITE_CODES.contains(code) && retried < retries {
+                        retry.extend(docs.get(*index).cloned());
+                    } else {
+                        log_failure(db, collection, mode, verbose, &format!("Failed to write _id {}: {}", id, message));
+                        if let Some(doc) = docs.get(*index) {
+                            dead_letters.write(db, collection, doc, *code, message);
+                        };
+                        written.failed += 1;
+                        if *code == 11000 && mode == WriteMode::Fail {
+                            written.conflicts += 1;
+                        };
+                    };
+                }
+
+                // In fail mode an ordered write stops at a conflict, and nothing after it is written
+                if ordered && written.conflicts > 0 {
+                    if next < docs.len() {
+                        log::error!("{}.{}: Not writing {} docs after the conflict", db, collection, docs.len() - next);
+                    };
+                    break;
+                };
+
+                // Docs an ordered write never got to still have to be written
+                if next < docs.len() {
+                    pending.push((docs[next..].to_vec(), retried));
+                };
+
+                if !retry.is_empty() {
+                    let wait = backoff(retried);
+                    log::warn!("{}.{}: Retrying {} failed docs in {:?}, attempt {} of {}", db, collection, retry.len(), wait, retried + 1, retries);
+                    tokio::time::sleep(wait).await;
+                    pending.push((retry, retried + 1));
+                };
+            }
+        };
+    }
+
+    if written.duplicates > 0 {
+        log::debug!("{}.{}: Skipped {} docs that already exist", db, collection, written.duplicates);
+    };
+
+    metrics::add(metrics::BATCHES_IN_FLIGHT, &labels, -1.0);
+    written
+}
+
+// Count docs written, and their encoded size when metrics are served
+fn record_written<'a>(labels: &[(&str, &str)], docs: impl Iterator<Item = &'a Document>) -> u64 {
+    if !metrics::enabled() {
+        return docs.count() as u64
+    };
+    let (count, bytes) = docs.fold((0, 0), |(count, bytes), doc| (count + 1, bytes + doc_size(doc)));
+    metrics::add(metrics::DOCS_WRITTEN, labels, count as f64);
+    metrics::add(metrics::BYTES_WRITTEN, labels, bytes as f64);
+    count
+}
+
+fn record_error(namespace: &str, code: i32) {
+    metrics::add(metrics::WRITE_ERRORS, &[("namespace", namespace), ("code", &code.to_string())], 1.0);
+}
+
+// Write a batch once, as inserts or upserts
+async fn attempt(database: &Database, collection: &str, docs: &[Document], mode: WriteMode, ordered: bool) -> Result<(), Failure> {
+    match mode {
+        WriteMode::Upsert => upsert_many(database, collection, docs, ordered).await,
+        _ => {
+            let options = InsertManyOptions::builder().ordered(Some(ordered)).build();
+            match database.collection(collection).insert_many(docs.to_vec(), options).await {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    // Docs that were rejected one by one, when every other doc was written
+                    if let ErrorKind::BulkWriteError(failure) = e.kind.as_ref() {
+                        if let (None, Some(errors)) = (&failure.write_concern_error, &failure.write_errors) {
+                            if !errors.is_empty() {
+                                return Err(Failure::Docs(errors.iter().map(|e| (e.index, e.code, e.message.clone())).collect()))
+                            };
+                        };
+                    };
+                    let retry = retryable(&e);
+                    Err(Failure::Batch(e.into(), retry))
+                }
+            }
+        }
+    }
+}
+
+// Whether an error is transient, like a network error, a primary stepping down, or the server being busy
+fn retryable(e: &mongodb::error::Error) -> bool {
+    let code = match e.kind.as_ref() {
+        ErrorKind::Io(_) | ErrorKind::ConnectionPoolClearedError { .. } | ErrorKind::ServerSelectionError { .. } => return true,
+        ErrorKind::CommandError(err) => Some(err.code),
+        ErrorKind::WriteError(WriteFailure::WriteConcernError(err)) => Some(err.code),
+        ErrorKind::BulkWriteError(failure) => failure.write_concern_error.as_ref().map(|err| err.code),
+        _ => None
+    };
+
+    code.map(|code| RETRYABLE_WRITE_CODES.contains(&code)).unwrap_or(false)
+        || e.contains_label("RetryableWriteError")
+        || e.contains_label("NetworkError")
+}
+
+// Server error code of a failed write, or 0 when the server did not send one
+fn error_code(e: &(dyn error::Error + Send + Sync + 'static)) -> i32 {
+    match e.downcast_ref::<mongodb::error::Error>().map(|e| e.kind.as_ref()) {
+        Some(ErrorKind::CommandError(err)) => err.code,
+        Some(ErrorKind::WriteError(WriteFailure::WriteConcernError(err))) => err.code,
+        Some(ErrorKind::WriteError(WriteFailure::WriteError(err))) => err.code,
+        Some(ErrorKind::BulkWriteError(failure)) => failure.write_concern_error.as_ref().map(|err| err.code).unwrap_or(0),
+        _ => 0
+    }
+}
+
+// Exponential backoff with full jitter, so that concurrent tasks do not all retry at once
+fn backoff(retried: u32) -> Duration {
+    let ceiling = RETRY_BASE.checked_mul(2u32.saturating_pow(retried)).unwrap_or(RETRY_MAX).min(RETRY_MAX);
+    ceiling.mul_f64(rand::thread_rng().gen_range(0.0..1.0))
+}
+
+// Write errors are expected with --write_mode fail, otherwise they are only shown with --verbose
+fn log_failure(db: &str, collection: &str, mode: WriteMode, verbose: bool, message: &str) {
+    if verbose || mode == WriteMode::Fail {
+        log::error!("{}.{}: {}", db, collection, message);
+    } else {
+        log::debug!("{}.{}: {}", db, collection, message);
+    }
+}
+
+// Whether the server rejected a write for the size of the request.
+// A doc too large on its own comes back as a per doc write error instead, and fails without splitting the batch
+fn too_large(e: &(dyn error::Error + Send + Sync + 'static)) -> bool {
+    match e.downcast_ref::<mongodb::error::Error>().map(|e| e.kind.as_ref()) {
+        Some(ErrorKind::CommandError(err)) => TOO_LARGE_CODES.contains(&err.code),
+        Some(ErrorKind::WriteError(WriteFailure::WriteError(err))) => TOO_LARGE_CODES.contains(&err.code),
+        _ => false
+    }
 }
 
-pub async fn validate(mut source_db: DB, mut destination_db: DB, _opts: ArgMatches<'_>, collection: String) -> BoxResult<()> {
-    // Open cursor of all docs in destination
-    let (destination_cursor,counter) = destination_db.find(&collection, None, None).await?;
+// Encoded BSON size of a doc
+pub fn doc_size(doc: &Document) -> usize {
+    let mut bytes = Vec::new();
+    match doc.to_writer(&mut bytes) {
+        Ok(_) => bytes.len(),
+        Err(_) => 0
+    }
+}
+
+// Replace each doc by _id with upsert, sent as update commands, which is what a bulk write of replaceOne models sends
+async fn upsert_many(database: &Database, collection: &str, docs: &[Document], ordered: bool) -> Result<(), Failure> {
+    // Keep every command well under the 16MB limit, remembering where each one starts in the batch
+    let mut chunks: Vec<(usize, Vec<Document>)> = vec![(0, Vec::new())];
+    let mut size = 0;
+    for (index, doc) in docs.iter().enumerate() {
+        let update = doc!{ "q": { "_id": doc.get("_id").cloned().unwrap_or(Bson::Null) }, "u": doc.clone(), "upsert": true };
+        let mut bytes = Vec::new();
+        update.to_writer(&mut bytes).ok();
+        if size + bytes.len() > UPDATE_COMMAND_BYTES && !chunks.last().map(|c| c.1.is_empty()).unwrap_or(true) {
+            chunks.push((index, Vec::new()));
+            size = 0;
+        };
+        size += bytes.len();
+        if let Some(chunk) = chunks.last_mut() {
+            chunk.1.push(update);
+        };
+    };
+
+    let mut errors = Vec::new();
+    for (start, updates) in chunks {
+        if updates.is_empty() {
+            continue;
+        };
+
+        let chunk_errors = update_command(database, collection, updates, ordered).await?;
+        let failed = !chunk_errors.is_empty();
+        errors.extend(chunk_errors.into_iter().map(|(index, code, message)| (start + index, code, message)));
+
+        // Ordered upserts stop at the first error
+        if ordered && failed {
+            break;
+        };
+    };
 
-    // Make sure all docs in destination exist in source
-    source_db.validate_docs(&collection, destination_cursor, counter).await?;
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(Failure::Docs(errors))
+    }
+}
+
+// Run an update command, returning the write errors of the updates that failed
+async fn update_command(database: &Database, collection: &str, updates: Vec<Document>, ordered: bool) -> Result<Vec<(usize, i32, String)>, Failure> {
+    let response = match database.run_command(doc!{ "update": collection, "updates": updates, "ordered": ordered }, None).await {
+        Ok(response) => response,
+        Err(e) => {
+            let retry = retryable(&e);
+            return Err(Failure::Batch(e.into(), retry))
+        }
+    };
+
+    // Every update was applied, but not acknowledged by enough members
+    if let Ok(error) = response.get_document("writeConcernError") {
+        let code = error.get_i32("code").unwrap_or(0);
+        return Err(Failure::Batch(format!("Write concern error: {}", error).into(), RETRYABLE_WRITE_CODES.contains(&code)))
+    };
+
+    // Write errors are returned in a successful response
+    let errors = response.get_array("writeErrors").map(|errors| {
+        errors.iter()
+            .filter_map(|e| e.as_document())
+            .map(|e| (e.get_i32("index").unwrap_or(0) as usize, e.get_i32("code").unwrap_or(0), e.get_str("errmsg").unwrap_or("").to_string()))
+            .collect()
+    }).unwrap_or_default();
+
+    Ok(errors)
+}
+
+// Get the user supplied projection for a source collection
+pub fn projection(opts: &ArgMatches<'_>, db: &str, collection: &str) -> BoxResult<Option<Document>> {
+    let projection = PerNamespace::new(opts.values_of("project").into_iter().flatten().collect())?.get(db, collection);
+    if projection.is_empty() {
+        return Ok(None)
+    };
+
+    // --continue and validation both rely on _id being copied as is
+    if let Some(id) = projection.get("_id") {
+        if id.as_i32() == Some(0) || id.as_i64() == Some(0) || id.as_bool() == Some(false) {
+            return Err(format!("{}.{}: Projection {} cannot exclude _id", db, collection, projection).into())
+        };
+    };
+
+    log::debug!("{}.{}: Using projection {}", db, collection, projection);
+    Ok(Some(projection))
+}
+
+pub async fn transfer_view(source_db: DB, destination_db: DB, view: Document, mapping: Mapping, drop: bool) -> BoxResult<()> {
+    let source_view = view.get_str("name")?.to_string();
+    let (db, destination_view) = mapping.map(&source_db.db, &source_view);
+
+    // Options hold viewOn, pipeline and collation, which create takes as is
+    let mut options = view.get_document("options").cloned().unwrap_or_default();
+
+    // Point the view at its collection's new name, which only works if both end up in the same database
+    if let Ok(view_on) = options.get_str("viewOn").map(|v| v.to_string()) {
+        let (view_on_db, destination_view_on) = mapping.map(&source_db.db, &view_on);
+        if view_on_db != db {
+            log::warn!("{}.{}: View depends on {}, which is moved to {}.{} at destination", source_db.db, source_view, view_on, view_on_db, destination_view_on);
+        } else if destination_view_on != view_on {
+            log::warn!("{}.{}: View depends on {}, which is renamed to {} at destination", source_db.db, source_view, view_on, destination_view_on);
+            options.insert("viewOn", destination_view_on);
+        };
+    };
+
+    let target_db = destination_db.database(&source_db.db, Some(&db));
+    if drop {
+        target_db.drop_collection(&destination_view).await?;
+    };
+
+    log::info!("{}.{}: Recreating view on {}", source_db.db, source_view, options.get_str("viewOn").unwrap_or("unknown"));
+    target_db.create_collection(&destination_view, options).await?;
 
     Ok(())
 }
+
+// _ids of docs to copy again from source, and of destination docs that are not in source
+#[derive(Clone, Debug, Default)]
+pub struct Mismatches {
+    pub recopy: Vec<Bson>,
+    pub extra: Vec<Bson>
+}
+
+// Upsert mismatched docs from source, optionally delete destination only docs, then check each of them again.
+// Returns how many docs still do not match.
+#[allow(clippy::too_many_arguments)]
+pub async fn repair(source_db: &DB, target_db: &DB, collection: &str, destination_collection: &str, projection: Option<Document>, ids: &Mismatches, delete: bool, limiter: &Limiter) -> BoxResult<u64> {
+    let source_handle = source_db.client.database(&source_db.db).collection(collection);
+    let destination_handle = target_db.client.database(&target_db.db).collection(destination_collection);
+    let destination = format!("{}.{}", target_db.db, destination_collection);
+
+    let find_one_options = FindOneOptions::builder().projection(projection.clone()).build();
+    let replace_options = ReplaceOptions::builder().upsert(true).build();
+
+    log::info!("{}.{}: Repairing {} docs at {}", source_db.db, collection, ids.recopy.len() + ids.extra.len(), destination);
+
+    for id in &ids.recopy {
+        match source_handle.find_one(doc!{ "_id": id.clone() }, find_one_options.clone()).await? {
+            Some(doc) => {
+                limiter.acquire_doc(&doc).await;
+                destination_handle.replace_one(doc!{ "_id": id.clone() }, doc, replace_options.clone()).await?;
+                log::debug!("{}.{}: Copied {} to {}", source_db.db, collection, id, destination);
+            },
+            None => {
+                // Deleted from source since it was validated
+                destination_handle.delete_one(doc!{ "_id": id.clone() }, None).await?;
+                log::debug!("{}.{}: Deleted {} from {}, since it is no longer in source", source_db.db, collection, id, destination);
+            }
+        };
+    };
+
+    for id in &ids.extra {
+        match delete {
+            true => {
+                destination_handle.delete_one(doc!{ "_id": id.clone() }, None).await?;
+                log::debug!("{}.{}: Deleted {} from {}", source_db.db, collection, id, destination);
+            },
+            false => log::warn!("{}.{}: Keeping {} at {}, pass --repair_delete to delete docs that are not in source", source_db.db, collection, id, destination)
+        };
+    };
+
+    // Check each repaired doc again
+    let mut unresolved = 0;
+    let repaired = ids.recopy.iter().chain(ids.extra.iter().filter(|_| delete));
+    for id in repaired {
+        let source_doc = source_handle.find_one(doc!{ "_id": id.clone() }, find_one_options.clone()).await?;
+        if let Some(doc) = &source_doc {
+            limiter.acquire_doc(doc).await;
+        };
+        let destination_doc = destination_handle.find_one(doc!{ "_id": id.clone() }, None).await?;
+        let matched = match (&source_doc, &destination_doc) {
+            (Some(s), Some(d)) => hash(s)? == hash(d)?,
+            (None, None) => true,
+            _ => false
+        };
+        if !matched {
+            log::error!("{}.{}: Doc {} still differs at {} after repair", source_db.db, collection, id, destination);
+            unresolved += 1;
+        };
+    };
+
+    if !delete {
+        unresolved += ids.extra.len() as u64;
+    };
+
+    match unresolved {
+        0 => log::info!(db = source_db.db.as_str(), collection, phase = "repair", unresolved;
+            "{}.{}: Repaired all mismatches at {}", source_db.db, collection, destination),
+        _ => log::error!(db = source_db.db.as_str(), collection, phase = "repair", unresolved;
+            "{}.{}: {} mismatches remain at {} after repair", source_db.db, collection, unresolved, destination)
+    };
+
+    Ok(unresolved)
+}
+
+// Hash the encoded doc, so that any change to a value, its type or the field order is detected
+pub fn hash(doc: &Document) -> BoxResult<[u8; 16]> {
+    let mut bytes = Vec::new();
+    doc.to_writer(&mut bytes)?;
+    Ok(md5::compute(bytes).0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+
+    #[test]
+    fn bound_after_an_object_id_includes_later_types() {
+        let id = ObjectId::new();
+        assert_eq!(bound(Bson::ObjectId(id.clone()), "$gt"), doc! {
+            "$or": [
+                { "_id": { "$gt": id } },
+                { "_id": { "$type": ["bool", "date", "timestamp", "regex", "maxKey"] } }
+            ]
+        });
+    }
+
+    #[test]
+    fn bound_before_an_object_id_includes_earlier_types() {
+        let id = ObjectId::new();
+        assert_eq!(bound(Bson::ObjectId(id.clone()), "$lt"), doc! {
+            "$or": [
+                { "_id": { "$lt": id } },
+                { "_id": { "$type": ["minKey", "null", "undefined", "number", "string", "symbol", "object", "array", "binData"] } }
+            ]
+        });
+    }
+
+    #[test]
+    fn bound_on_a_string_includes_symbols_only_by_value() {
+        assert_eq!(bound(Bson::String("m".to_string()), "$gte"), doc! {
+            "$or": [
+                { "_id": { "$gte": "m" } },
+                { "_id": { "$type": ["object", "array", "binData", "objectId", "bool", "date", "timestamp", "regex", "maxKey"] } }
+            ]
+        });
+        assert_eq!(bound(Bson::String("m".to_string()), "$lt"), doc! {
+            "$or": [
+                { "_id": { "$lt": "m" } },
+                { "_id": { "$type": ["minKey", "null", "undefined", "number"] } }
+            ]
+        });
+    }
+
+    #[test]
+    fn bound_on_numbers_is_the_same_for_every_numeric_type() {
+        let expected = doc! {
+            "$or": [
+                { "_id": { "$lt": 10 } },
+                { "_id": { "$type": ["minKey", "null", "undefined"] } }
+            ]
+        };
+        assert_eq!(bound(Bson::Int32(10), "$lt"), expected);
+
+        for id in [Bson::Int64(10), Bson::Double(10.0)] {
+            let filter = bound(id.clone(), "$gte");
+            let types = filter.get_array("$or").unwrap()[1].as_document().unwrap().get_document("_id").unwrap().get_array("$type").unwrap().clone();
+            assert_eq!(filter.get_array("$or").unwrap()[0], Bson::Document(doc! { "_id": { "$gte": id } }));
+            assert_eq!(types.first(), Some(&Bson::String("string".to_string())));
+            assert_eq!(types.last(), Some(&Bson::String("maxKey".to_string())));
+        }
+    }
+
+    #[test]
+    fn bound_at_the_ends_of_the_type_order_is_a_plain_comparison() {
+        assert_eq!(bound(Bson::MinKey, "$lt"), doc! { "_id": { "$lt": Bson::MinKey } });
+        assert_eq!(bound(Bson::MaxKey, "$gt"), doc! { "_id": { "$gt": Bson::MaxKey } });
+        assert_eq!(bound(Bson::MaxKey, "$gte"), doc! { "_id": { "$gte": Bson::MaxKey } });
+
+        let filter = bound(Bson::MinKey, "$gte");
+        assert_eq!(filter.get_array("$or").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn bound_on_an_unordered_type_is_a_plain_comparison() {
+        let code = Bson::JavaScriptCode("x".to_string());
+        assert_eq!(bound(code.clone(), "$gt"), doc! { "_id": { "$gt": code } });
+    }
+
+    #[test]
+    fn type_order_follows_the_server_sort_order() {
+        let values = [
+            Bson::MinKey,
+            Bson::Null,
+            Bson::Int32(1),
+            Bson::String("a".to_string()),
+            Bson::Document(doc! {}),
+            Bson::Array(vec![]),
+            Bson::ObjectId(ObjectId::new()),
+            Bson::Boolean(true),
+            Bson::MaxKey
+        ];
+        let orders: Vec<usize> = values.iter().map(|v| type_order(v).unwrap()).collect();
+        assert!(orders.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(type_order(&Bson::MaxKey), Some(BSON_TYPE_ORDER.len() - 1));
+    }
+
+    #[test]
+    fn type_order_groups_equivalent_types() {
+        assert_eq!(type_order(&Bson::Int32(1)), type_order(&Bson::Int64(1)));
+        assert_eq!(type_order(&Bson::Int32(1)), type_order(&Bson::Double(1.0)));
+        assert_eq!(type_order(&Bson::Null), type_order(&Bson::Undefined));
+        assert_eq!(type_order(&Bson::String("a".to_string())), type_order(&Bson::Symbol("a".to_string())));
+        assert_eq!(type_order(&Bson::JavaScriptCode("x".to_string())), None);
+    }
+}
@@ -0,0 +1,115 @@
+use bson::{doc, Document};
+use clap::ArgMatches;
+use std::error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::db::{write_batch, WriteMode, Written, DB};
+use crate::limit::Limiter;
+
+type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+// BSON file of docs that could not be written, shared by every insert task, which does nothing without --dead_letter
+#[derive(Clone, Debug, Default)]
+pub struct DeadLetters {
+    file: Option<Arc<Mutex<BufWriter<File>>>>
+}
+
+impl DeadLetters {
+    // Append to the file, so that dead letters of earlier runs are kept
+    pub fn open(path: Option<&str>) -> BoxResult<Self> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None
+        };
+
+        Ok(DeadLetters {
+            file: file.map(|f| Arc::new(Mutex::new(BufWriter::new(f))))
+        })
+    }
+
+    // Record a doc with the error that stopped it from being written, as raw BSON so that every type, Decimal128 included, survives a replay
+    pub fn write(&self, db: &str, collection: &str, doc: &Document, code: i32, message: &str) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return
+        };
+
+        let entry = doc! {
+            "namespace": format!("{}.{}", db, collection),
+            "code": code,
+            "message": message,
+            "doc": doc.clone()
+        };
+
+        // Flushed on every write, since failures are rare and the process may exit without dropping the writer
+        let mut bytes = Vec::new();
+        let result = entry.to_writer(&mut bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|_| {
+                let mut file = file.lock().unwrap();
+                file.write_all(&bytes).and_then(|_| file.flush()).map_err(|e| e.to_string())
+            });
+
+        if let Err(e) = result {
+            log::error!("{}.{}: Failed to write dead letter: {}", db, collection, e);
+        };
+    }
+}
+
+// Write every doc in a dead letter file to its destination namespace again, keeping the docs that still fail in the file
+pub async fn replay(destination_db: DB, opts: ArgMatches<'_>, path: &str, limiter: Limiter) -> BoxResult<Written> {
+    let mode = WriteMode::new(opts.value_of("write_mode").unwrap_or("insert"));
+    let retries = opts.value_of("retries").unwrap_or("5").parse::<u32>()?;
+    let bulk_size = match opts.is_present("bulk") {
+        true => opts.value_of("bulk").unwrap().parse::<usize>()?,
+        false => 2000
+    };
+
+    // Group docs by namespace, in the order they failed
+    let mut namespaces: Vec<(String, Vec<Document>)> = Vec::new();
+    let contents = fs::read(path)?;
+    let mut reader = contents.as_slice();
+    let mut number = 0;
+    while !reader.is_empty() {
+        number += 1;
+        let entry = Document::from_reader(&mut reader)
+            .map_err(|e| format!("Dead letter {} of {} cannot be read: {}", number, path, e))?;
+        let namespace = entry.get_str("namespace")?.to_string();
+        let doc = entry.get_document("doc")?.clone();
+
+        match namespaces.iter_mut().find(|(ns, _)| *ns == namespace) {
+            Some((_, docs)) => docs.push(doc),
+            None => namespaces.push((namespace, vec![doc]))
+        };
+    }
+
+    // Docs that fail again go to a new file, which replaces the old one once every doc has been tried
+    let tmp = format!("{}.tmp", path);
+    fs::remove_file(&tmp).ok();
+    let dead_letters = DeadLetters::open(Some(&tmp))?;
+
+    let mut written = Written::default();
+    for (namespace, docs) in namespaces {
+        let (db, collection) = match namespace.split_once('.') {
+            Some(ns) => ns,
+            None => return Err(format!("Dead letter namespace {} is not db.collection", namespace).into())
+        };
+        let database = destination_db.client.database(db);
+
+        log::info!("{}.{}: Replaying {} dead letters", db, collection, docs.len());
+
+        let mut replayed = Written::default();
+        for batch in docs.chunks(bulk_size) {
+            replayed.add(write_batch(&database, collection, batch.to_vec(), mode, false, true, retries, &limiter, &dead_letters).await);
+        }
+
+        log::info!(db, collection, phase = "replay", count = docs.len(), duplicates = replayed.duplicates, failed = replayed.failed;
+            "{}.{}: Replayed {} docs, skipped {} duplicates, {} failed again", db, collection, docs.len(), replayed.duplicates, replayed.failed);
+        written.add(replayed);
+    }
+
+    fs::rename(&tmp, path)?;
+    Ok(written)
+}
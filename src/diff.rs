@@ -0,0 +1,410 @@
+use bson::{doc, Bson, Document};
+use clap::ArgMatches;
+use futures::StreamExt;
+use mongodb::Cursor;
+use std::cmp::Ordering;
+use std::error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::db::{has_decimal128, hash, projection, repair, type_order, Mismatches, DB};
+use crate::filter::PerNamespace;
+use crate::limit::Limiter;
+
+type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+// JSON lines report, shared by all collections being diffed
+pub type Report = Arc<Mutex<BufWriter<File>>>;
+
+// Counts of differences found in a collection
+#[derive(Clone, Debug, Default)]
+pub struct Diff {
+    pub matched: u64,
+    pub source_only: u64,
+    pub destination_only: u64,
+    pub different: u64,
+    pub repaired: u64,
+    pub ids: Mismatches
+}
+
+impl Diff {
+    pub fn differences(&self) -> u64 {
+        self.source_only + self.destination_only + self.different
+    }
+
+    // Differences left after any repair
+    pub fn unresolved(&self) -> u64 {
+        self.differences() - self.repaired
+    }
+}
+
+// Walk source and destination in _id order as a sorted merge join, and find every _id that is not in both, or differs.
+// Differences are written to the report when there is one, as for the diff command, and logged otherwise, as for --validate
+pub async fn diff(mut source_db: DB, destination_db: DB, opts: ArgMatches<'_>, collection: String, rename_coll: Option<String>, report: Option<Report>, limiter: Limiter) -> BoxResult<Diff> {
+    let phase = match report {
+        Some(_) => "diff",
+        None => "validate"
+    };
+
+    // If renamecoll is Some
+    let destination_collection = match rename_coll {
+        Some(c) => c,
+        None => collection.clone()
+    };
+
+    // Read from the renamed destination db, if there is one
+    let mut target_db = match &destination_db.renamedb {
+        Some(db) => destination_db.database(db, None),
+        None => destination_db.clone()
+    };
+
+    let source_ns = format!("{}.{}", source_db.db, collection);
+    let destination_ns = format!("{}.{}", target_db.db, destination_collection);
+
+    // Both sides are read with the same query, and source docs with the same projection as during the transfer,
+    // so that skipped docs and dropped fields are not reported, and destination docs outside the query are neither reported nor deleted by --repair_delete
+    let query = PerNamespace::new(opts.values_of("query").into_iter().flatten().collect())?.get(&source_db.db, &collection);
+    let projection = projection(&opts, &source_db.db, &collection)?;
+
+    let (mut source_cursor, counter) = source_db.find(&collection, None, None, query.clone(), projection.clone()).await?;
+    let (mut destination_cursor, _) = target_db.find(&destination_collection, None, None, query, None).await?;
+
+    log::info!("{}: Comparing {} docs against {}", source_ns, counter.total(), destination_ns);
+    counter.set_phase(phase);
+    let start = Instant::now();
+
+    // Ids are only kept to be repaired, so that comparing a large collection does not grow in memory
+    let collect = opts.is_present("repair");
+
+    let mut diff = Diff::default();
+    merge(&mut source_cursor, &mut destination_cursor, &source_ns, &destination_ns, &limiter, |merged| {
+        match merged {
+            Merged::SourceOnly(doc) => {
+                found(report.as_ref(), &source_ns, &destination_ns, id(doc), "source_only")?;
+                diff.source_only += 1;
+                if collect {
+                    diff.ids.recopy.push(id(doc).clone());
+                };
+                counter.incr(&source_db.db, &collection, 1.0, start);
+            },
+            Merged::DestinationOnly(doc) => {
+                found(report.as_ref(), &source_ns, &destination_ns, id(doc), "destination_only")?;
+                diff.destination_only += 1;
+                if collect {
+                    diff.ids.extra.push(id(doc).clone());
+                };
+            },
+            Merged::Both(s, d) => {
+                if hash(s)? == hash(d)? {
+                    diff.matched += 1;
+                } else {
+                    found(report.as_ref(), &source_ns, &destination_ns, id(s), "different")?;
+                    diff.different += 1;
+                    if collect {
+                        diff.ids.recopy.push(id(s).clone());
+                    };
+                };
+                counter.incr(&source_db.db, &collection, 1.0, start);
+            }
+        };
+        Ok(())
+    }).await?;
+
+    if let Some(report) = &report {
+        report.lock().unwrap().flush()?;
+    };
+
+    match diff.differences() {
+        0 => log::info!(db = source_db.db.as_str(), collection = collection.as_str(), phase, matched = diff.matched;
+            "{}: No differences in {} docs", source_ns, diff.matched),
+        _ => log::error!(db = source_db.db.as_str(), collection = collection.as_str(), phase, matched = diff.matched, source_only = diff.source_only, destination_only = diff.destination_only, different = diff.different;
+            "{}: Found {} docs only in source, {} only in destination and {} different, {} matched", source_ns, diff.source_only, diff.destination_only, diff.different, diff.matched)
+    };
+
+    // Fix just the docs that differ, rather than copying the whole collection again
+    if opts.is_present("repair") && diff.differences() > 0 {
+        let unresolved = repair(&source_db, &target_db, &collection, &destination_collection, projection, &diff.ids, opts.is_present("repair_delete"), &limiter).await?;
+        diff.repaired = diff.differences() - unresolved;
+    };
+
+    Ok(diff)
+}
+
+// Where an _id was found by a merge join
+pub enum Merged<'a> {
+    SourceOnly(&'a Document),
+    DestinationOnly(&'a Document),
+    Both(&'a Document, &'a Document)
+}
+
+// Walk two cursors sorted by _id side by side, handing every _id to f along with the docs that have it.
+// Source reads are held to the rate limits, which the destination cursor keeps pace with
+pub async fn merge<F>(source_cursor: &mut Cursor, destination_cursor: &mut Cursor, source_ns: &str, destination_ns: &str, limiter: &Limiter, mut f: F) -> BoxResult<()>
+where
+    F: FnMut(Merged) -> BoxResult<()>
+{
+    let mut source = next(source_cursor, source_ns, None, Some(limiter)).await?;
+    let mut destination = next(destination_cursor, destination_ns, None, None).await?;
+
+    loop {
+        let status = match (&source, &destination) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(s), Some(d)) => compare(id(s), id(d))
+        };
+
+        match status {
+            Ordering::Less => {
+                let doc = source.take().unwrap();
+                f(Merged::SourceOnly(&doc))?;
+                source = next(source_cursor, source_ns, Some(doc), Some(limiter)).await?;
+            },
+            Ordering::Greater => {
+                let doc = destination.take().unwrap();
+                f(Merged::DestinationOnly(&doc))?;
+                destination = next(destination_cursor, destination_ns, Some(doc), None).await?;
+            },
+            Ordering::Equal => {
+                let (s, d) = (source.take().unwrap(), destination.take().unwrap());
+                f(Merged::Both(&s, &d))?;
+                source = next(source_cursor, source_ns, Some(s), Some(limiter)).await?;
+                destination = next(destination_cursor, destination_ns, Some(d), None).await?;
+            }
+        };
+    }
+
+    Ok(())
+}
+
+// Get the next doc from a cursor, checking that the server returned it after the previous one
+async fn next(cursor: &mut Cursor, ns: &str, previous: Option<Document>, limiter: Option<&Limiter>) -> BoxResult<Option<Document>> {
+    let doc = match cursor.next().await {
+        Some(doc) => doc?,
+        None => return Ok(None)
+    };
+
+    if let Some(limiter) = limiter {
+        limiter.acquire_doc(&doc).await;
+    };
+
+    // Decimal128 values cannot be compared or written to the report without decimal support in bson
+    if has_decimal128(id(&doc)) {
+        return Err(format!("{}: _id {} contains a Decimal128, cannot compare collections with Decimal128 _ids", ns, id(&doc)).into())
+    };
+
+    // A collection default collation orders string _ids differently, which would break the merge join
+    if let Some(previous) = previous {
+        if compare(id(&previous), id(&doc)) != Ordering::Less {
+            return Err(format!("{}: _id {} was returned after {}, cannot compare a collection whose _ids do not sort in binary order", ns, id(&doc), id(&previous)).into())
+        };
+    };
+
+    Ok(Some(doc))
+}
+
+pub fn id(doc: &Document) -> &Bson {
+    doc.get("_id").unwrap_or(&Bson::Null)
+}
+
+// Write a difference to the report, or log it when there is none
+fn found(report: Option<&Report>, source_ns: &str, destination_ns: &str, id: &Bson, status: &str) -> BoxResult<()> {
+    let report = match report {
+        Some(report) => report,
+        None => {
+            match status {
+                "source_only" => log::error!("{}: Doc {} is missing at destination {}", source_ns, id, destination_ns),
+                "destination_only" => log::error!("{}: Doc {} at destination {} is not in source", source_ns, id, destination_ns),
+                _ => log::error!("{}: Doc {} differs at destination {}", source_ns, id, destination_ns)
+            };
+            return Ok(())
+        }
+    };
+
+    let entry = doc! {
+        "source": source_ns,
+        "destination": destination_ns,
+        "_id": id.clone(),
+        "status": status
+    };
+    let line = serde_json::to_string(&Bson::Document(entry).into_relaxed_extjson())?;
+    writeln!(report.lock().unwrap(), "{}", line)?;
+    Ok(())
+}
+
+// Compare two values the way the server sorts them, first by type and then by value
+pub fn compare(a: &Bson, b: &Bson) -> Ordering {
+    match type_order(a).cmp(&type_order(b)) {
+        Ordering::Equal => (),
+        ordering => return ordering
+    };
+
+    match (a, b) {
+        (Bson::Int32(_), Bson::Int32(_)) | (Bson::Int32(_), Bson::Int64(_)) | (Bson::Int64(_), Bson::Int32(_)) | (Bson::Int64(_), Bson::Int64(_)) => {
+            integer(a).cmp(&integer(b))
+        },
+        (Bson::Int32(_), _) | (Bson::Int64(_), _) | (Bson::Double(_), _) => {
+            number(a).partial_cmp(&number(b)).unwrap_or(Ordering::Equal)
+        },
+        (Bson::String(a), Bson::String(b)) | (Bson::Symbol(a), Bson::Symbol(b)) | (Bson::String(a), Bson::Symbol(b)) | (Bson::Symbol(a), Bson::String(b)) => {
+            a.as_bytes().cmp(b.as_bytes())
+        },
+        (Bson::Document(a), Bson::Document(b)) => {
+            // Fields are compared in order, by type, then name, then value
+            for (fa, fb) in a.iter().zip(b.iter()) {
+                let ordering = type_order(fa.1).cmp(&type_order(fb.1))
+                    .then_with(|| fa.0.as_bytes().cmp(fb.0.as_bytes()))
+                    .then_with(|| compare(fa.1, fb.1));
+                if ordering != Ordering::Equal {
+                    return ordering
+                };
+            };
+            a.len().cmp(&b.len())
+        },
+        (Bson::Array(a), Bson::Array(b)) => {
+            for (ea, eb) in a.iter().zip(b.iter()) {
+                let ordering = compare(ea, eb);
+                if ordering != Ordering::Equal {
+                    return ordering
+                };
+            };
+            a.len().cmp(&b.len())
+        },
+        (Bson::Binary(a), Bson::Binary(b)) => {
+            a.bytes.len().cmp(&b.bytes.len())
+                .then_with(|| u8::from(a.subtype).cmp(&u8::from(b.subtype)))
+                .then_with(|| a.bytes.cmp(&b.bytes))
+        },
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.bytes().cmp(&b.bytes()),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.timestamp_millis().cmp(&b.timestamp_millis()),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => (a.time, a.increment).cmp(&(b.time, b.increment)),
+        (Bson::RegularExpression(a), Bson::RegularExpression(b)) => {
+            a.pattern.as_bytes().cmp(b.pattern.as_bytes()).then_with(|| a.options.as_bytes().cmp(b.options.as_bytes()))
+        },
+        _ => Ordering::Equal
+    }
+}
+
+fn integer(value: &Bson) -> i64 {
+    match value {
+        Bson::Int32(i) => *i as i64,
+        Bson::Int64(i) => *i,
+        _ => 0
+    }
+}
+
+fn number(value: &Bson) -> f64 {
+    match value {
+        Bson::Int32(i) => *i as f64,
+        Bson::Int64(i) => *i as f64,
+        Bson::Double(f) => *f,
+        _ => f64::NAN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+    use bson::spec::BinarySubtype;
+    use bson::{Binary, Regex, Timestamp};
+
+    fn binary(subtype: BinarySubtype, bytes: &[u8]) -> Bson {
+        Bson::Binary(Binary { subtype, bytes: bytes.to_vec() })
+    }
+
+    #[test]
+    fn numbers_compare_across_types() {
+        assert_eq!(compare(&Bson::Int32(1), &Bson::Int64(2)), Ordering::Less);
+        assert_eq!(compare(&Bson::Int64(3), &Bson::Int32(2)), Ordering::Greater);
+        assert_eq!(compare(&Bson::Int32(2), &Bson::Double(2.5)), Ordering::Less);
+        assert_eq!(compare(&Bson::Double(-1.5), &Bson::Int64(-2)), Ordering::Greater);
+        assert_eq!(compare(&Bson::Double(1.0), &Bson::Int32(1)), Ordering::Equal);
+    }
+
+    #[test]
+    fn large_integers_compare_exactly() {
+        // Both round to the same double
+        assert_eq!(compare(&Bson::Int64(i64::MAX - 1), &Bson::Int64(i64::MAX)), Ordering::Less);
+        assert_eq!(compare(&Bson::Int32(i32::MAX), &Bson::Int64(i32::MAX as i64 + 1)), Ordering::Less);
+    }
+
+    #[test]
+    fn documents_compare_field_names_before_values() {
+        let a = Bson::Document(doc! { "a": 2 });
+        let b = Bson::Document(doc! { "b": 1 });
+        assert_eq!(compare(&a, &b), Ordering::Less);
+        assert_eq!(compare(&b, &a), Ordering::Greater);
+    }
+
+    #[test]
+    fn documents_with_the_same_fields_compare_values() {
+        assert_eq!(compare(&Bson::Document(doc! { "a": 1, "b": 2 }), &Bson::Document(doc! { "a": 1, "b": 3 })), Ordering::Less);
+        assert_eq!(compare(&Bson::Document(doc! { "a": 1 }), &Bson::Document(doc! { "a": 1.0 })), Ordering::Equal);
+    }
+
+    #[test]
+    fn documents_compare_value_types_before_field_names() {
+        // A number sorts before a string, whatever the field names
+        let number = Bson::Document(doc! { "z": 1 });
+        let string = Bson::Document(doc! { "a": "x" });
+        assert_eq!(compare(&number, &string), Ordering::Less);
+    }
+
+    #[test]
+    fn shorter_documents_and_arrays_sort_first() {
+        assert_eq!(compare(&Bson::Document(doc! { "a": 1 }), &Bson::Document(doc! { "a": 1, "b": 1 })), Ordering::Less);
+        assert_eq!(compare(&Bson::Array(vec![Bson::Int32(1)]), &Bson::Array(vec![Bson::Int32(1), Bson::Int32(0)])), Ordering::Less);
+        assert_eq!(compare(&Bson::Array(vec![Bson::Int32(2)]), &Bson::Array(vec![Bson::Int32(1), Bson::Int32(5)])), Ordering::Greater);
+    }
+
+    #[test]
+    fn binary_compares_length_then_subtype_then_bytes() {
+        let short = binary(BinarySubtype::UserDefined(0x80), &[9]);
+        let long = binary(BinarySubtype::Generic, &[0, 0]);
+        assert_eq!(compare(&short, &long), Ordering::Less);
+
+        let generic = binary(BinarySubtype::Generic, &[9, 9]);
+        let uuid = binary(BinarySubtype::Uuid, &[0, 0]);
+        assert_eq!(compare(&generic, &uuid), Ordering::Less);
+
+        let low = binary(BinarySubtype::Generic, &[1, 2]);
+        let high = binary(BinarySubtype::Generic, &[1, 3]);
+        assert_eq!(compare(&low, &high), Ordering::Less);
+        assert_eq!(compare(&low, &low.clone()), Ordering::Equal);
+    }
+
+    #[test]
+    fn strings_compare_by_bytes_and_match_symbols() {
+        assert_eq!(compare(&Bson::String("B".to_string()), &Bson::String("a".to_string())), Ordering::Less);
+        assert_eq!(compare(&Bson::String("a".to_string()), &Bson::Symbol("a".to_string())), Ordering::Equal);
+    }
+
+    #[test]
+    fn types_compare_in_server_order() {
+        let values = [
+            Bson::MinKey,
+            Bson::Null,
+            Bson::Double(f64::MAX),
+            Bson::String(String::new()),
+            Bson::Document(doc! {}),
+            Bson::Array(vec![]),
+            binary(BinarySubtype::Generic, &[]),
+            Bson::ObjectId(ObjectId::with_bytes([0; 12])),
+            Bson::Boolean(false),
+            Bson::DateTime(chrono::Utc::now()),
+            Bson::Timestamp(Timestamp { time: 0, increment: 0 }),
+            Bson::RegularExpression(Regex { pattern: String::new(), options: String::new() }),
+            Bson::MaxKey
+        ];
+        for (i, a) in values.iter().enumerate() {
+            for (j, b) in values.iter().enumerate() {
+                assert_eq!(compare(a, b), i.cmp(&j), "{} vs {}", a, b);
+            }
+        }
+        assert_eq!(compare(&Bson::Null, &Bson::Undefined), Ordering::Equal);
+    }
+}
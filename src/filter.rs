@@ -0,0 +1,270 @@
+use bson::{Bson, Document};
+use regex::Regex;
+use std::convert::TryFrom;
+use std::error;
+
+type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+// Databases that are never copied when listing every database on the source
+pub const SYSTEM_DBS: [&str; 3] = ["admin", "local", "config"];
+
+// Include and exclude patterns, matched against db.collection namespaces
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub include: Vec<Regex>,
+    pub exclude: Vec<Regex>
+}
+
+impl Filter {
+    pub fn new(include: Vec<&str>, exclude: Vec<&str>) -> BoxResult<Self> {
+        Ok(Filter {
+            include: include.into_iter().map(pattern).collect::<BoxResult<Vec<Regex>>>()?,
+            exclude: exclude.into_iter().map(pattern).collect::<BoxResult<Vec<Regex>>>()?
+        })
+    }
+
+    // A namespace is selected if it matches any include pattern, or there are none, and no exclude pattern
+    pub fn matches(&self, namespace: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|r| r.is_match(namespace));
+        let excluded = self.exclude.iter().any(|r| r.is_match(namespace));
+        included && !excluded
+    }
+}
+
+// Patterns wrapped in slashes are regexes, anything else is a glob where * matches any run of characters and ? a single one
+pub fn pattern(pattern: &str) -> BoxResult<Regex> {
+    if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+        return Ok(Regex::new(&pattern[1..pattern.len() - 1])?)
+    };
+
+    Ok(Regex::new(&format!("^{}$", glob_to_regex(pattern)))?)
+}
+
+// Escape a glob, turning each wildcard into a capture group
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str("(.*)"),
+            '?' => regex.push_str("(.)"),
+            _ => regex.push_str(&regex::escape(&c.to_string()))
+        }
+    }
+    regex
+}
+
+// Namespace mapping rules, applied to db.collection in order, where the first matching rule wins
+#[derive(Clone, Debug, Default)]
+pub struct Mapping {
+    pub rules: Vec<(Regex, String)>
+}
+
+impl Mapping {
+    pub fn new(from: Vec<&str>, to: Vec<&str>) -> BoxResult<Self> {
+        if from.len() != to.len() {
+            return Err("Each --ns_from needs a matching --ns_to".into())
+        };
+
+        let mut mapping = Mapping::default();
+        for (from, to) in from.into_iter().zip(to) {
+            mapping.push(from, to)?;
+        };
+        Ok(mapping)
+    }
+
+    // Each * in to is replaced with what the matching wildcard in from matched
+    pub fn push(&mut self, from: &str, to: &str) -> BoxResult<()> {
+        if !from.contains('.') || !to.contains('.') {
+            return Err(format!("Mapping {} to {} must use db.collection namespaces", from, to).into())
+        };
+
+        let wildcards = from.matches(['*', '?']).count();
+        if wildcards != to.matches('*').count() {
+            return Err(format!("Mapping {} to {} must use the same number of wildcards", from, to).into())
+        };
+
+        let regex = Regex::new(&format!("^{}$", glob_to_regex(from)))?;
+        self.rules.push((regex, to.to_string()));
+        Ok(())
+    }
+
+    // Get the destination db and collection for a source namespace
+    pub fn map(&self, db: &str, collection: &str) -> (String, String) {
+        let namespace = format!("{}.{}", db, collection);
+
+        for (from, to) in &self.rules {
+            if let Some(captures) = from.captures(&namespace) {
+                let mut mapped = String::new();
+                let mut groups = captures.iter().skip(1);
+                for c in to.chars() {
+                    match c {
+                        '*' => mapped.push_str(groups.next().flatten().map(|m| m.as_str()).unwrap_or("")),
+                        _ => mapped.push(c)
+                    }
+                }
+
+                // Database names cannot contain dots, so the first dot splits db from collection
+                return match mapped.split_once('.') {
+                    Some((db, collection)) => (db.to_string(), collection.to_string()),
+                    None => (mapped, collection.to_string())
+                }
+            }
+        }
+
+        (db.to_string(), collection.to_string())
+    }
+}
+
+// User supplied documents in extended json, such as queries or projections, either global or for db.collection namespaces matching a pattern
+#[derive(Clone, Debug, Default)]
+pub struct PerNamespace {
+    pub global: Document,
+    pub namespaces: Vec<(Regex, Document)>
+}
+
+impl PerNamespace {
+    // Each value is either a json object, or a pattern and a json object separated by =
+    pub fn new(values: Vec<&str>) -> BoxResult<Self> {
+        let mut queries = PerNamespace::default();
+        for value in values {
+            let value = value.trim();
+            if value.starts_with('{') {
+                queries.global = parse_json(value)?;
+            } else {
+                match value.split_once('=') {
+                    Some((namespace, query)) => queries.namespaces.push((pattern(namespace.trim())?, parse_json(query)?)),
+                    None => return Err(format!("{} must be a json object, or namespace={{...}}", value).into())
+                }
+            }
+        };
+        Ok(queries)
+    }
+
+    // The first namespace that matches wins, otherwise the global document is used
+    pub fn get(&self, db: &str, collection: &str) -> Document {
+        let namespace = format!("{}.{}", db, collection);
+        self.namespaces.iter()
+            .find(|(regex, _)| regex.is_match(&namespace))
+            .map(|(_, query)| query.clone())
+            .unwrap_or_else(|| self.global.clone())
+    }
+}
+
+// Parse an extended json object into a document
+pub fn parse_json(json: &str) -> BoxResult<Document> {
+    let value: serde_json::Value = serde_json::from_str(json.trim())?;
+    match Bson::try_from(value)? {
+        Bson::Document(doc) => Ok(doc),
+        _ => Err(format!("{} is not a json object", json).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn rules(rules: &[(&str, &str)]) -> Mapping {
+        Mapping::new(rules.iter().map(|r| r.0).collect(), rules.iter().map(|r| r.1).collect()).unwrap()
+    }
+
+    fn mapped(db: &str, collection: &str) -> (String, String) {
+        (db.to_string(), collection.to_string())
+    }
+
+    #[test]
+    fn map_renames_exact_namespaces() {
+        let mapping = rules(&[("shop.users", "crm.customers")]);
+        assert_eq!(mapping.map("shop", "users"), mapped("crm", "customers"));
+        assert_eq!(mapping.map("shop", "orders"), mapped("shop", "orders"));
+    }
+
+    #[test]
+    fn map_expands_star_captures_in_order() {
+        let mapping = rules(&[("*.logs_*", "archive.*_*")]);
+        assert_eq!(mapping.map("shop", "logs_2021"), mapped("archive", "shop_2021"));
+    }
+
+    #[test]
+    fn map_fills_stars_with_question_mark_captures() {
+        // ? counts as a wildcard in from, but only * is expanded in to
+        let mapping = rules(&[("shop.events_?", "shop.archive_*")]);
+        assert_eq!(mapping.map("shop", "events_1"), mapped("shop", "archive_1"));
+        assert_eq!(mapping.map("shop", "events_12"), mapped("shop", "events_12"));
+
+        let mapping = rules(&[("shop.?_*", "*.*")]);
+        assert_eq!(mapping.map("shop", "a_users"), mapped("a", "users"));
+    }
+
+    #[test]
+    fn map_keeps_a_literal_question_mark_in_to() {
+        let mapping = rules(&[("shop.*", "shop.*?")]);
+        assert_eq!(mapping.map("shop", "users"), mapped("shop", "users?"));
+    }
+
+    #[test]
+    fn map_uses_the_first_matching_rule() {
+        let mapping = rules(&[("shop.users", "crm.users"), ("shop.*", "archive.*")]);
+        assert_eq!(mapping.map("shop", "users"), mapped("crm", "users"));
+        assert_eq!(mapping.map("shop", "orders"), mapped("archive", "orders"));
+    }
+
+    #[test]
+    fn map_splits_db_at_the_first_dot() {
+        let mapping = rules(&[("shop.*", "archive.shop.*")]);
+        assert_eq!(mapping.map("shop", "users"), mapped("archive", "shop.users"));
+    }
+
+    #[test]
+    fn mapping_rejects_invalid_rules() {
+        assert!(Mapping::new(vec!["shop.*"], vec![]).is_err());
+        assert!(Mapping::new(vec!["shop"], vec!["crm"]).is_err());
+        assert!(Mapping::new(vec!["shop.*"], vec!["crm.users"]).is_err());
+        assert!(Mapping::new(vec!["shop.?"], vec!["crm.?"]).is_err());
+    }
+
+    #[test]
+    fn pattern_matches_globs() {
+        let glob = pattern("shop.user?_*").unwrap();
+        assert!(glob.is_match("shop.users_2021"));
+        assert!(glob.is_match("shop.users_"));
+        assert!(!glob.is_match("shop.user_2021"));
+        assert!(!glob.is_match("shopXusers_2021"));
+        assert!(!glob.is_match("old.shop.users_2021"));
+    }
+
+    #[test]
+    fn pattern_takes_regexes_in_slashes() {
+        let regex = pattern("/^shop\\.(users|orders)$/").unwrap();
+        assert!(regex.is_match("shop.users"));
+        assert!(!regex.is_match("shop.carts"));
+        assert!(pattern("/(/").is_err());
+
+        // A lone slash is a glob
+        assert!(pattern("/").unwrap().is_match("/"));
+    }
+
+    #[test]
+    fn per_namespace_prefers_the_first_matching_namespace() {
+        let queries = PerNamespace::new(vec![
+            r#"{"active": true}"#,
+            r#"shop.users={"name": "acme"}"#,
+            r#"shop.*={"deleted": false}"#
+        ]).unwrap();
+        assert_eq!(queries.get("shop", "users"), doc! { "name": "acme" });
+        assert_eq!(queries.get("shop", "orders"), doc! { "deleted": false });
+        assert_eq!(queries.get("crm", "users"), doc! { "active": true });
+    }
+
+    #[test]
+    fn per_namespace_defaults_to_an_empty_document() {
+        let queries = PerNamespace::new(vec![r#"shop.users={"name": "acme"}"#]).unwrap();
+        assert_eq!(queries.get("shop", "orders"), Document::new());
+    }
+
+    #[test]
+    fn per_namespace_rejects_values_without_a_document() {
+        assert!(PerNamespace::new(vec!["shop.users"]).is_err());
+        assert!(PerNamespace::new(vec!["shop.users=[1]"]).is_err());
+    }
+}
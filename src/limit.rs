@@ -0,0 +1,136 @@
+use std::error;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bson::Document;
+
+use crate::db::doc_size;
+use crate::filter::parse_json;
+
+type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+// Rate limits shared by every collection and insert task, and by every read of the source to compare, repair or follow it
+pub type Limiter = Arc<RateLimiter>;
+
+// Token buckets for docs and bytes written per second, where a rate of zero means unlimited
+#[derive(Debug)]
+pub struct RateLimiter {
+    docs: Mutex<Bucket>,
+    bytes: Mutex<Bucket>
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        Bucket {
+            rate,
+            tokens: rate,
+            updated: Instant::now()
+        }
+    }
+
+    // Take tokens, going into debt if there are not enough, and return how long to wait until the debt is paid off
+    fn take(&mut self, count: f64) -> Duration {
+        if self.rate <= 0.0 {
+            return Duration::from_secs(0)
+        };
+
+        // Refill, allowing at most one second worth of burst
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.rate);
+        self.updated = now;
+
+        self.tokens -= count;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::from_secs(0)
+        }
+    }
+
+    fn set_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate);
+    }
+}
+
+impl RateLimiter {
+    pub fn new(docs: f64, bytes: f64) -> Limiter {
+        Arc::new(RateLimiter {
+            docs: Mutex::new(Bucket::new(docs)),
+            bytes: Mutex::new(Bucket::new(bytes))
+        })
+    }
+
+    // Wait until a batch of docs can be written without going over either limit
+    pub async fn acquire(&self, docs: usize, bytes: usize) {
+        let wait = {
+            let docs_wait = self.docs.lock().unwrap().take(docs as f64);
+            let bytes_wait = self.bytes.lock().unwrap().take(bytes as f64);
+            docs_wait.max(bytes_wait)
+        };
+
+        if wait > Duration::from_secs(0) {
+            log::debug!("Rate limited, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+        };
+    }
+
+    // Wait until a single doc can be read or written, only sizing it when bytes are limited
+    pub async fn acquire_doc(&self, doc: &Document) {
+        let bytes = match self.limits_bytes() {
+            true => doc_size(doc),
+            false => 0
+        };
+        self.acquire(1, bytes).await;
+    }
+
+    // Whether bytes are limited, since sizing docs is not free
+    pub fn limits_bytes(&self) -> bool {
+        self.bytes.lock().unwrap().rate > 0.0
+    }
+
+    pub fn set_limits(&self, docs: f64, bytes: f64) {
+        self.docs.lock().unwrap().set_rate(docs);
+        self.bytes.lock().unwrap().set_rate(bytes);
+        log::info!("Rate limits set to {} docs/s and {} bytes/s, 0 is unlimited", docs, bytes);
+    }
+
+    // Read max_docs_per_sec and max_bytes_per_sec from a json file, missing limits are unlimited
+    pub fn reload(&self, path: &str) -> BoxResult<()> {
+        let limits = parse_json(&fs::read_to_string(path)?)?;
+        let limit = |key: &str| -> f64 {
+            limits.get(key).and_then(|v| v.as_f64().or_else(|| v.as_i64().map(|i| i as f64)).or_else(|| v.as_i32().map(|i| i as f64))).unwrap_or(0.0)
+        };
+        self.set_limits(limit("max_docs_per_sec"), limit("max_bytes_per_sec"));
+        Ok(())
+    }
+}
+
+// Reload the limits file whenever the process gets a SIGHUP
+#[cfg(unix)]
+pub fn watch_limits(limiter: Limiter, path: String) -> BoxResult<()> {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match limiter.reload(&path) {
+                Ok(_) => log::info!("Reloaded rate limits from {}", path),
+                Err(e) => log::error!("Failed to reload rate limits from {}: {}", path, e)
+            };
+        }
+    });
+    Ok(())
+}
+
+// There is no SIGHUP outside of unix, so the limits file is only read at startup
+#[cfg(not(unix))]
+pub fn watch_limits(_limiter: Limiter, path: String) -> BoxResult<()> {
+    log::warn!("Rate limits in {} cannot be reloaded on this platform, restart to change them", path);
+    Ok(())
+}
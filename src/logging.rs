@@ -0,0 +1,154 @@
+use chrono::Local;
+use env_logger::{Builder, Target};
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Record};
+use regex::Regex;
+use serde_json::json;
+use std::io::Write;
+use std::sync::OnceLock;
+
+// Fields that come first in every line, in this order
+const LEADING: [&str; 3] = ["db", "collection", "phase"];
+
+// Log messages start with the db.collection namespace they are about
+fn namespace_prefix() -> &'static Regex {
+    static PREFIX: OnceLock<Regex> = OnceLock::new();
+    PREFIX.get_or_init(|| Regex::new(r"^([^\s.:]+)\.([^\s:]+): ").unwrap())
+}
+
+// Log to stdout as one json object per line, or as plain text
+pub fn init(format: &str) {
+    let json = format == "json";
+    Builder::new()
+        .format(move |buf, record| {
+            let date = Local::now().format("%Y-%m-%dT%H:%M:%S:%f").to_string();
+            writeln!(buf, "{}", line(record, &date, json))
+        })
+        .target(Target::Stdout)
+        .filter_level(LevelFilter::Info)
+        .parse_default_env()
+        .init();
+}
+
+// A record as a json object with its fields and an escaped message, or as text with the fields after the message
+fn line(record: &Record, date: &str, json: bool) -> String {
+    let fields = fields(record);
+    match json {
+        true => {
+            let mut line = format!("{{\"date\": {}, \"level\": {}", json!(date), json!(record.level().as_str()));
+            for (key, value) in &fields {
+                line.push_str(&format!(", {}: {}", json!(key), value));
+            }
+            line.push_str(&format!(", \"message\": {}}}", json!(record.args().to_string())));
+            line
+        },
+        false => {
+            let mut line = format!("{} {:<5} {}", date, record.level(), record.args());
+            for (key, value) in &fields {
+                // db and collection are already at the start of the message
+                if key != "db" && key != "collection" {
+                    line.push_str(&format!(" {}={}", key, value.as_str().map(String::from).unwrap_or_else(|| value.to_string())));
+                };
+            }
+            line
+        }
+    }
+}
+
+// Structured fields of a record, from its key values, falling back to the namespace the message starts with
+fn fields(record: &Record) -> Vec<(String, serde_json::Value)> {
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    let mut fields = fields.0;
+
+    if !fields.iter().any(|(key, _)| key == "db") {
+        let message = record.args().to_string();
+        if let Some(captures) = namespace_prefix().captures(&message) {
+            fields.push(("db".to_string(), json!(&captures[1])));
+            fields.push(("collection".to_string(), json!(&captures[2])));
+        };
+    };
+
+    fields.sort_by_key(|(key, _)| LEADING.iter().position(|k| k == key).unwrap_or(LEADING.len()));
+    fields
+}
+
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        // Keep counters as json numbers
+        let value = if let Some(v) = value.to_u64() {
+            json!(v)
+        } else if let Some(v) = value.to_i64() {
+            json!(v)
+        } else if let Some(v) = value.to_f64() {
+            json!(v)
+        } else if let Some(v) = value.to_bool() {
+            json!(v)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.push((key.as_str().to_string(), value));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn parse(line: &str) -> serde_json::Value {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn json_lines_escape_messages() {
+        let line = line(&Record::builder().level(Level::Error).args(format_args!("Bad \"doc\"\nat {}", "C:\\tmp")).build(), "now", true);
+        let value = parse(&line);
+        assert_eq!(value["message"], "Bad \"doc\"\nat C:\\tmp");
+        assert_eq!(value["level"], "ERROR");
+        assert_eq!(value["date"], "now");
+    }
+
+    #[test]
+    fn json_lines_keep_numbers_as_numbers() {
+        let kvs: [(&str, log::kv::Value); 4] = [
+            ("count", log::kv::Value::from(5u64)),
+            ("offset", log::kv::Value::from(-3i64)),
+            ("rate", log::kv::Value::from(1.5f64)),
+            ("phase", log::kv::Value::from("copy"))
+        ];
+        let line = line(&Record::builder().level(Level::Info).args(format_args!("Copied")).key_values(&kvs).build(), "now", true);
+        let value = parse(&line);
+        assert_eq!(value["count"], json!(5));
+        assert_eq!(value["offset"], json!(-3));
+        assert_eq!(value["rate"], json!(1.5));
+        assert_eq!(value["phase"], "copy");
+    }
+
+    #[test]
+    fn namespace_prefix_fills_db_and_collection() {
+        let line = line(&Record::builder().level(Level::Info).args(format_args!("shop.users: Inserting 5 docs")).build(), "now", true);
+        let value = parse(&line);
+        assert_eq!(value["db"], "shop");
+        assert_eq!(value["collection"], "users");
+
+        // db and collection lead the fields
+        assert!(line.find("\"db\"").unwrap() < line.find("\"message\"").unwrap());
+    }
+
+    #[test]
+    fn messages_without_a_namespace_have_no_db() {
+        let line = line(&Record::builder().level(Level::Info).args(format_args!("Loaded state from a.state")).build(), "now", true);
+        assert!(parse(&line).get("db").is_none());
+    }
+
+    #[test]
+    fn text_lines_append_fields_after_the_message() {
+        let kvs = [("count", 5u64)];
+        let line = line(&Record::builder().level(Level::Info).args(format_args!("shop.users: Copied")).key_values(&kvs).build(), "now", false);
+        assert_eq!(line, "now INFO  shop.users: Copied count=5");
+    }
+}
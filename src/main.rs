@@ -1,15 +1,33 @@
-use chrono::Local;
-use clap::{crate_version, App, Arg};
-use env_logger::{Builder, Target};
-use log::LevelFilter;
-use std::io::Write;
+use clap::{crate_version, App, Arg, SubCommand};
+use std::io::{BufWriter, Write};
+use std::fs::File;
 use std::error;
-use db::{DB, transfer, validate};
+use db::{DB, transfer, transfer_view};
+use dead_letter::{replay, DeadLetters};
+use diff::{diff, Report};
+use stream::{can_resume, follow, namespace, operation_time};
+use state::{Checkpoint, SharedState, State};
+use limit::{watch_limits, Limiter, RateLimiter};
+use summary::{Outcome, Summary};
+use progress::JobProgress;
+use filter::{Filter, Mapping, PerNamespace, SYSTEM_DBS};
 //use bson::doc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use bson::Document;
 use tokio::sync::Semaphore;
 
 mod db;
+mod dead_letter;
+mod diff;
+mod filter;
+mod limit;
+mod logging;
+mod metrics;
+mod progress;
+mod state;
+mod stream;
+mod summary;
 
 type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
 
@@ -41,12 +59,44 @@ async fn main() -> BoxResult<()> {
             Arg::with_name("db")
                 .short("d")
                 .long("db")
-                .required(true)
+                .required_unless("all_dbs")
                 .value_name("MONGODB_DB")
                 .env("MONGODB_DB")
                 .help("MongoDB Database")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("all_dbs")
+                .long("all_dbs")
+                .required(false)
+                .value_name("STREAM_ALL_DBS")
+                .env("STREAM_ALL_DBS")
+                .help("Copy all databases, except admin, local and config")
+                .conflicts_with_all(&["db", "collection"])
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .required(false)
+                .value_name("STREAM_INCLUDE")
+                .env("STREAM_INCLUDE")
+                .help("Only copy db.collection namespaces matching this glob, or /regex/")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .required(false)
+                .value_name("STREAM_EXCLUDE")
+                .env("STREAM_EXCLUDE")
+                .help("Skip db.collection namespaces matching this glob, or /regex/")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+        )
         .arg(
             Arg::with_name("collection")
                 .short("c")
@@ -68,6 +118,17 @@ async fn main() -> BoxResult<()> {
                 .conflicts_with("nobulk")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("bulk_bytes")
+                .long("bulk_bytes")
+                .required(false)
+                .value_name("STREAM_BULK_BYTES")
+                .env("STREAM_BULK_BYTES")
+                .help("Largest encoded size of a bulk batch in bytes")
+                .conflicts_with("nobulk")
+                .default_value("16777216")
+                .takes_value(true)
+        )
         .arg(
             Arg::with_name("continue")
                 .short("c")
@@ -105,7 +166,26 @@ async fn main() -> BoxResult<()> {
                 .required(false)
                 .value_name("MONGODB_VALIDATE")
                 .env("MONGODB_VALIDATE")
-                .help("Validate docs in destination")
+                .help("Compare every doc in destination with source by content hash")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("repair")
+                .long("repair")
+                .required(false)
+                .value_name("STREAM_REPAIR")
+                .env("STREAM_REPAIR")
+                .help("Copy docs found missing or different by --validate or diff again from source")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("repair_delete")
+                .long("repair_delete")
+                .required(false)
+                .value_name("STREAM_REPAIR_DELETE")
+                .env("STREAM_REPAIR_DELETE")
+                .help("With --repair, also delete destination docs that are not in source")
+                .requires("repair")
                 .takes_value(false)
         )
         .arg(
@@ -135,28 +215,237 @@ async fn main() -> BoxResult<()> {
                 .help("Rename collection at destination")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("query")
+                .long("query")
+                .required(false)
+                .value_name("STREAM_QUERY")
+                .env("STREAM_QUERY")
+                .help("Only copy docs matching this extended json query, or namespace={query} for matching db.collection namespaces")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("project")
+                .long("project")
+                .required(false)
+                .value_name("STREAM_PROJECT")
+                .env("STREAM_PROJECT")
+                .help("Only copy fields selected by this extended json projection, or namespace={projection} for matching db.collection namespaces")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("ns_from")
+                .long("ns_from")
+                .required(false)
+                .value_name("STREAM_NS_FROM")
+                .env("STREAM_NS_FROM")
+                .help("Source db.collection namespace to rename, * matches any run of characters")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("ns_to")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("ns_to")
+                .long("ns_to")
+                .required(false)
+                .value_name("STREAM_NS_TO")
+                .env("STREAM_NS_TO")
+                .help("Destination db.collection namespace for the matching --ns_from, each * is replaced by what it matched")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("ns_from")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("indexes")
+                .long("indexes")
+                .required(false)
+                .value_name("STREAM_INDEXES")
+                .env("STREAM_INDEXES")
+                .help("When to build source indexes at destination")
+                .possible_values(&["before", "after", "none"])
+                .default_value("after")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("partitions")
+                .long("partitions")
+                .required(false)
+                .value_name("STREAM_PARTITIONS")
+                .env("STREAM_PARTITIONS")
+                .help("Split each collection into this many _id ranges, copied concurrently")
+                .conflicts_with("continue")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max_docs_per_sec")
+                .long("max_docs_per_sec")
+                .required(false)
+                .value_name("STREAM_MAX_DOCS_PER_SEC")
+                .env("STREAM_MAX_DOCS_PER_SEC")
+                .help("Most docs copied per second, across all collections, also applied to source reads by --validate, diff, --repair and --follow")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max_bytes_per_sec")
+                .long("max_bytes_per_sec")
+                .required(false)
+                .value_name("STREAM_MAX_BYTES_PER_SEC")
+                .env("STREAM_MAX_BYTES_PER_SEC")
+                .help("Most bytes copied per second, across all collections, also applied to source reads by --validate, diff, --repair and --follow")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("limits_file")
+                .long("limits_file")
+                .required(false)
+                .value_name("STREAM_LIMITS_FILE")
+                .env("STREAM_LIMITS_FILE")
+                .help("Json file with max_docs_per_sec and max_bytes_per_sec, read at startup and reloaded on SIGHUP")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("drop")
+                .long("drop")
+                .required(false)
+                .value_name("STREAM_DROP")
+                .env("STREAM_DROP")
+                .help("Drop each destination collection and view before copying it")
+                .conflicts_with_all(&["continue", "resume"])
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("yes")
+                .long("yes")
+                .required(false)
+                .value_name("STREAM_YES")
+                .env("STREAM_YES")
+                .help("Do not ask for confirmation before dropping destination collections")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("write_mode")
+                .long("write_mode")
+                .required(false)
+                .value_name("STREAM_WRITE_MODE")
+                .env("STREAM_WRITE_MODE")
+                .help("How to write docs whose _id already exists at destination: skip them, replace them, or stop the collection")
+                .possible_values(&["insert", "upsert", "fail"])
+                .default_value("insert")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("dead_letter")
+                .long("dead_letter")
+                .required(false)
+                .value_name("STREAM_DEAD_LETTER")
+                .env("STREAM_DEAD_LETTER")
+                .help("BSON file to append docs that could not be written to, with the error and namespace")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .long("log_format")
+                .required(false)
+                .value_name("STREAM_LOG_FORMAT")
+                .env("STREAM_LOG_FORMAT")
+                .help("Log one json object per line with db, collection, phase and counters as fields, or plain text")
+                .possible_values(&["json", "text"])
+                .default_value("json")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("metrics_addr")
+                .long("metrics_addr")
+                .required(false)
+                .value_name("STREAM_METRICS_ADDR")
+                .env("STREAM_METRICS_ADDR")
+                .help("Address to serve Prometheus metrics on at /metrics, such as 0.0.0.0:9187")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .required(false)
+                .value_name("STREAM_SUMMARY")
+                .env("STREAM_SUMMARY")
+                .help("Format of the per collection summary printed at the end of the copy")
+                .possible_values(&["table", "json"])
+                .default_value("table")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .required(false)
+                .value_name("STREAM_RETRIES")
+                .env("STREAM_RETRIES")
+                .help("Times to retry a write after a transient error, with exponential backoff")
+                .default_value("5")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("follow")
+                .long("follow")
+                .required(false)
+                .value_name("STREAM_FOLLOW")
+                .env("STREAM_FOLLOW")
+                .help("Keep replaying changes from the source after the initial copy")
+                .conflicts_with_all(&["continue", "project", "query"])
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("state_file")
+                .long("state_file")
+                .required(false)
+                .value_name("STREAM_STATE_FILE")
+                .env("STREAM_STATE_FILE")
+                .help("File to persist change stream resume tokens and collection checkpoints in")
+                .default_value("mongodb-stream-rs.state")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .required(false)
+                .value_name("STREAM_RESUME")
+                .env("STREAM_RESUME")
+                .help("Skip collections already copied, and resume others from their last checkpoint")
+                .conflicts_with("continue")
+                .takes_value(false)
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compare source and destination collections in _id order, without copying")
+                .arg(
+                    Arg::with_name("report")
+                        .long("report")
+                        .required(false)
+                        .value_name("STREAM_DIFF_REPORT")
+                        .env("STREAM_DIFF_REPORT")
+                        .help("JSON lines file to write differences to")
+                        .default_value("mongodb-stream-rs.diff.jsonl")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("replay-dead-letters")
+                .about("Write the docs in --dead_letter to their destination again, keeping the ones that still fail")
+        )
         .get_matches();
 
-    // Initialize log Builder
-    Builder::new()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{{\"date\": \"{}\", \"level\": \"{}\", \"message\": \"{}\"}}",
-                Local::now().format("%Y-%m-%dT%H:%M:%S:%f"),
-                record.level(),
-                record.args()
-            )
-        })
-        .target(Target::Stdout)
-        .filter_level(LevelFilter::Info)
-        .parse_default_env()
-        .init();
+    // Initialize logging
+    logging::init(opts.value_of("log_format").unwrap_or("json"));
 
     // Create vars for required variables
     let source = &opts.value_of("source").unwrap();
     let destination = &opts.value_of("destination").unwrap();
-    let db = &opts.value_of("db").unwrap();
+    let db = &opts.value_of("db").unwrap_or("admin");
     let renamedb = &opts.value_of("rename_db");
 
     println!(
@@ -164,37 +453,144 @@ async fn main() -> BoxResult<()> {
         crate_version!(),
     );
 
+    // Serve metrics for the whole run, including while following changes
+    if let Some(addr) = opts.value_of("metrics_addr") {
+        metrics::serve(addr).await?;
+    };
+
     // Create connections to source and destination db's
-    let source_db = DB::init(&source, &db, None).await?;
-    let destination_db = DB::init(&destination, &db, *renamedb).await?;
+    let source_db = DB::init(source, db, None).await?;
+    let destination_db = DB::init(destination, db, *renamedb).await?;
+
+    // Build include and exclude filters for db.collection namespaces
+    let filter = Filter::new(
+        opts.values_of("include").map(|v| v.collect()).unwrap_or_default(),
+        opts.values_of("exclude").map(|v| v.collect()).unwrap_or_default()
+    )?;
 
-    // Collect all collections into array
-    let collections = match &opts.is_present("collection") {
+    // Check user supplied queries and projections up front, rather than failing every collection
+    for arg in &["query", "project"] {
+        if let Err(e) = PerNamespace::new(opts.values_of(arg).into_iter().flatten().collect()) {
+            log::error!("Invalid --{}: {}", arg, e);
+            std::process::exit(1);
+        };
+    };
+
+    // Collect all databases into array
+    let databases = match opts.is_present("all_dbs") {
         true => {
-            let mut vec: Vec<String> = Vec::new();
-            let coll = &opts.value_of("collection").unwrap();
-            vec.push(coll.to_string());
-            vec
+            let mut databases = source_db.databases().await?;
+            databases.retain(|d| {
+                if SYSTEM_DBS.contains(&d.as_str()) {
+                    log::info!("{}: Skipping system database", d);
+                    false
+                } else {
+                    true
+                }
+            });
+            databases
         },
-        false => source_db.collections().await?
+        false => vec![db.to_string()]
     };
 
-    let rename_coll = match opts.is_present("rename_coll") {
-        true => {
-            if collections.len() > 1 {
-                log::error!("Cannot rename a collection when multiple collections are found");
-                std::process::exit(1);
-            } else {
-                let x = opts.value_of("rename_coll").unwrap().to_string();
-                Some(x)
+    if renamedb.is_some() && databases.len() > 1 {
+        log::error!("Cannot rename a database when multiple databases are found");
+        std::process::exit(1);
+    };
+
+    // Collect all collections and views from every database
+    let mut collections: Vec<(DB, String)> = Vec::new();
+    let mut views: Vec<(DB, Document)> = Vec::new();
+
+    for database in &databases {
+        let source = source_db.database(database, None);
+
+        // Classify namespaces, so that views and system collections are not copied as data
+        let namespaces = source.namespaces().await?;
+        for system in &namespaces.system {
+            log::info!("{}.{}: Skipping system collection", database, system);
+        };
+
+        let selected = |name: &str| -> bool {
+            match opts.value_of("collection") {
+                Some(coll) => name == coll,
+                None => filter.matches(&format!("{}.{}", database, name))
             }
-        },
-        _ => None
+        };
+
+        for coll in namespaces.collections {
+            if selected(&coll) {
+                collections.push((source.clone(), coll));
+            };
+        };
+
+        for view in namespaces.views {
+            if selected(view.get_str("name")?) {
+                views.push((source.clone(), view));
+            };
+        };
     };
-            
 
-    // Create vector for handles
-    let mut handles = vec![];
+    if let Some(coll) = opts.value_of("collection") {
+        if collections.is_empty() && views.is_empty() {
+            log::warn!("{}.{}: Collection not found in source", db, coll);
+        };
+    };
+
+    log::info!("Found {} collections and {} views in {} databases", collections.len(), views.len(), databases.len());
+
+    // --rename_coll and --rename_db are turned into mapping rules, ahead of any --ns_from/--ns_to rules
+    let mut ns_from: Vec<String> = Vec::new();
+    let mut ns_to: Vec<String> = Vec::new();
+
+    if opts.is_present("rename_coll") {
+        if collections.len() + views.len() > 1 {
+            log::error!("Cannot rename a collection when multiple collections are found");
+            std::process::exit(1);
+        };
+        let source_coll = match (collections.first(), views.first()) {
+            (Some((_, coll)), _) => coll.as_str(),
+            (None, Some((_, view))) => view.get_str("name")?,
+            (None, None) => opts.value_of("collection").unwrap_or_default()
+        };
+        ns_from.push(format!("{}.{}", db, source_coll));
+        ns_to.push(format!("{}.{}", renamedb.unwrap_or(db), opts.value_of("rename_coll").unwrap()));
+    };
+
+    if let Some(renamedb) = renamedb {
+        ns_from.push(format!("{}.*", db));
+        ns_to.push(format!("{}.*", renamedb));
+    };
+
+    ns_from.extend(opts.values_of("ns_from").into_iter().flatten().map(String::from));
+    ns_to.extend(opts.values_of("ns_to").into_iter().flatten().map(String::from));
+
+    let mapping = Mapping::new(
+        ns_from.iter().map(String::as_str).collect(),
+        ns_to.iter().map(String::as_str).collect()
+    )?;
+
+    // Refuse to merge several source namespaces into one destination namespace
+    let mut mapped = std::collections::HashMap::new();
+    for (source, coll) in &collections {
+        let (dest_db, dest_coll) = mapping.map(&source.db, coll);
+        if let Some(other) = mapped.insert(format!("{}.{}", dest_db, dest_coll), format!("{}.{}", source.db, coll)) {
+            log::error!("Both {} and {}.{} map to {}.{} at destination", other, source.db, coll, dest_db, dest_coll);
+            std::process::exit(1);
+        };
+    };
+
+    // Refuse to copy a namespace onto itself, which --drop would destroy
+    if source_db.same_deployment(source, &destination_db, destination).await? {
+        let names = collections.iter().map(|(source, coll)| (source.db.clone(), coll.to_string()))
+            .chain(views.iter().filter_map(|(source, view)| view.get_str("name").ok().map(|v| (source.db.clone(), v.to_string()))));
+        for (db, coll) in names {
+            if mapping.map(&db, &coll) == (db.clone(), coll.clone()) {
+                log::error!("{}.{}: Source and destination are the same namespace", db, coll);
+                std::process::exit(1);
+            };
+        };
+    };
 
     // Let's rate limit to just 4 collections at once
     let sem = match &opts.is_present("threads") {
@@ -212,39 +608,308 @@ async fn main() -> BoxResult<()> {
             }
         }
     };
+    metrics::track_semaphore(Arc::clone(&sem), sem.available_permits());
+
+    // Rate limits are shared by every collection. --limits_file takes precedence over the flags,
+    // and can be changed by sending a SIGHUP after editing it
+    let limiter: Limiter = RateLimiter::new(
+        opts.value_of("max_docs_per_sec").unwrap_or("0").parse::<f64>()?,
+        opts.value_of("max_bytes_per_sec").unwrap_or("0").parse::<f64>()?
+    );
+    if let Some(path) = opts.value_of("limits_file") {
+        limiter.reload(path)?;
+        log::info!("Loaded rate limits from {}", path);
+        watch_limits(limiter.clone(), path.to_string())?;
+    };
+
+    // Retry docs that could not be written in an earlier run, and exit non-zero if any still fail
+    if opts.subcommand_matches("replay-dead-letters").is_some() {
+        let path = match opts.value_of("dead_letter") {
+            Some(path) => path,
+            None => {
+                log::error!("replay-dead-letters needs --dead_letter");
+                std::process::exit(1);
+            }
+        };
+
+        let written = replay(destination_db.clone(), opts.clone(), path, limiter).await?;
+        if written.failed > 0 {
+            log::error!("{} docs still could not be written, and were kept in {}", written.failed, path);
+            std::process::exit(1);
+        };
+        return Ok(())
+    };
+
+    // diff only compares source and destination, and exits non-zero on any difference
+    if let Some(diff_opts) = opts.subcommand_matches("diff") {
+        let path = diff_opts.value_of("report").unwrap();
+        let report: Report = Arc::new(Mutex::new(BufWriter::new(File::create(path)?)));
+
+        let mut handles = vec![];
+        for (source, collection) in collections {
+            let opts = opts.clone();
+            let report = Arc::clone(&report);
+            let limiter = limiter.clone();
+
+            // Get destination namespace
+            let (destination, rename_coll) = destination_for(&destination_db, &mapping, &source.db, &collection);
+
+            // Get permission to kick off task
+            let permit = Arc::clone(&sem).acquire_owned().await;
+
+            handles.push(tokio::spawn(async move {
+                let _permit = permit;
+                match diff(source, destination, opts, collection, rename_coll, Some(report), limiter).await {
+                    Ok(diff) => diff.unresolved() > 0,
+                    Err(e) => {
+                        log::error!("Thread error: {}", e);
+                        true
+                    }
+                }
+            }));
+        };
+
+        let different = futures::future::join_all(handles).await.into_iter()
+            .filter(|result| !matches!(result, Ok(false)))
+            .count();
+
+        log::info!("Wrote diff report to {}", path);
+        if different > 0 {
+            log::error!("{} collections differ between source and destination", different);
+            std::process::exit(1);
+        };
+        return Ok(())
+    };
+
+    // Namespaces to follow changes on
+    let targets: Vec<(String, String)> = collections.iter()
+        .map(|(source, coll)| (source.db.clone(), coll.clone()))
+        .collect();
+
+    // Change stream resume tokens are saved per source namespace, along with a checkpoint per collection
+    let state: SharedState = Arc::new(Mutex::new(State::load(opts.value_of("state_file").unwrap())?));
+    let namespace = namespace(db, &opts);
+
+    // A token saved while following onto another destination would skip the initial copy of this one
+    let followed = stream::destination(destination, &namespace, &targets, &mapping).await?;
+    let mut token = state.lock().unwrap().resume_token(&namespace);
+    if opts.is_present("follow") && token.is_some() && state.lock().unwrap().destination(&namespace) != Some(followed.clone()) {
+        log::warn!("{}: Saved resume token was written while following another destination, ignoring it and copying again", namespace);
+        state.lock().unwrap().clear_resume_token(&namespace);
+        token = None;
+    };
+
+    // If a saved resume token is still in the oplog, skip the initial copy and resume the change stream
+    let resuming = match (opts.is_present("follow"), token) {
+        (true, Some(token)) => {
+            if can_resume(&source_db, &opts, &targets, &token).await? {
+                true
+            } else {
+                log::error!("{}: Saved resume token has aged out of the source oplog, falling back to a full resync", namespace);
+                let mut state = state.lock().unwrap();
+                state.clear_resume_token(&namespace);
+                state.save()?;
+                false
+            }
+        },
+        _ => false
+    };
+
+    // If --follow is set, mark where the change stream should start before any docs are read
+    let follow_start = match opts.is_present("follow") && !resuming {
+        true => {
+            state.lock().unwrap().set_destination(&namespace, followed);
+            let start_at = operation_time(&source_db).await?;
+            log::info!("{}: Change stream will start at {}", namespace, bson::Bson::Timestamp(start_at));
+            Some(start_at)
+        },
+        false => None
+    };
+
+    // If resuming a change stream, there are no collections to copy
+    let pending = match resuming {
+        true => Vec::new(),
+        false => collections.clone()
+    };
+
+    // Create vector for handles
+    let mut handles = vec![];
+
+    // Ask before dropping anything, unless --yes is set
+    if opts.is_present("drop") && !opts.is_present("yes") && !resuming {
+        let namespaces: Vec<String> = pending.iter().map(|(source, coll)| mapping.map(&source.db, coll))
+            .chain(views.iter().filter_map(|(source, view)| view.get_str("name").ok().map(|v| mapping.map(&source.db, v))))
+            .map(|(db, coll)| format!("{}.{}", db, coll))
+            .collect();
+        if !namespaces.is_empty() && !confirm(&format!("Drop {} destination namespaces: {}?", namespaces.len(), namespaces.join(", ")))? {
+            log::error!("Not dropping destination namespaces, exiting");
+            std::process::exit(1);
+        };
+    };
+
+    // Docs that could not be written are kept here, to be replayed later
+    let dead_letters = DeadLetters::open(opts.value_of("dead_letter"))?;
+
+    // Outcome of every collection and view
+    let mut summary = Summary::default();
+
+    // Size up every collection that will be copied, for the overall progress and ETA
+    let progress = JobProgress::new();
+    for (source, collection) in &pending {
+        if opts.is_present("resume") && Checkpoint::new(&state, &source.db, collection).status().as_deref() == Some("done") {
+            continue;
+        };
+        match source.stats(collection).await {
+            Ok((docs, bytes)) => progress.add(&format!("{}.{}", source.db, collection), docs, bytes),
+            Err(e) => log::warn!("{}.{}: Could not get collection stats: {}", source.db, collection, e)
+        };
+    };
 
     // Loop over collections and start uploading
-    for collection in collections {
+    for (source, collection) in pending {
 
-        let source = source_db.clone();
-        let destination = destination_db.clone();
         let opts = opts.clone();
-        let rename_coll = rename_coll.clone();
+        let limiter = limiter.clone();
+        let dead_letters = dead_letters.clone();
+        let progress = progress.clone();
+
+        // With --resume, collections that finished copying are skipped, otherwise every collection starts over
+        let checkpoint = Checkpoint::new(&state, &source.db, &collection);
+        match (opts.is_present("resume"), checkpoint.status()) {
+            (true, Some(status)) if status == "done" => {
+                log::info!("{}.{}: Already copied, skipping", source.db, collection);
+                let (db, coll) = mapping.map(&source.db, &collection);
+                summary.push(Outcome::new(format!("{}.{}", source.db, collection), format!("{}.{}", db, coll), "skipped"));
+                continue;
+            },
+            (true, Some(_)) => (),
+            _ => checkpoint.reset()
+        };
+
+        // Get destination namespace
+        let (destination, rename_coll) = destination_for(&destination_db, &mapping, &source.db, &collection);
 
         // Get permission to kick off task
         let permit = Arc::clone(&sem).acquire_owned().await;
 
+        let (db, coll) = mapping.map(&source.db, &collection);
+        let mut outcome = Outcome::new(format!("{}.{}", source.db, collection), format!("{}.{}", db, coll), "done");
+
         handles.push(tokio::spawn(async move {
             let _permit = permit;
-            match transfer(source.clone(), destination.clone(), opts.clone(), collection.clone(), rename_coll).await {
-                Ok(_) => {
-                    // Check docs
+            let start = Instant::now();
+            match transfer(source.clone(), destination.clone(), opts.clone(), collection.clone(), rename_coll.clone(), checkpoint.clone(), limiter.clone(), dead_letters, progress).await {
+                Ok((read, written)) => {
+                    outcome.read = read;
+                    outcome.duplicates = written.duplicates;
+                    outcome.failed = written.failed;
+                    outcome.written = written.written;
+                    if !written.ok() {
+                        outcome.status = "failed".to_string();
+                    };
+
+                    // Check docs, recording whether the collection passed validation
                     if opts.is_present("validate") {
-                        match validate(source, destination, opts, collection).await {
-                            Ok(_) => log::debug!("Thread shutdown"),
-                            Err(e) => log::error!("Thread error: {}", e)
+                        outcome.validation = match diff(source, destination, opts, collection, rename_coll, None, limiter.clone()).await {
+                            Ok(validation) if validation.unresolved() > 0 => Some("invalid".to_string()),
+                            Ok(_) => Some("valid".to_string()),
+                            Err(e) => {
+                                log::error!("Thread error: {}", e);
+                                outcome.error = Some(e.to_string());
+                                Some("error".to_string())
+                            }
                         };
                     };
                     log::debug!("Thread shutdown");
                 },
-                Err(e) => log::error!("Thread error: {}", e)
-            }
+                Err(e) => {
+                    log::error!("Thread error: {}", e);
+                    checkpoint.fail(&e.to_string());
+                    outcome.status = "failed".to_string();
+                    outcome.error = Some(e.to_string());
+                }
+            };
+            outcome.duration = start.elapsed();
+            outcome
         }));
 
     };
 
-    // Join all handles
-    futures::future::join_all(handles).await;
+    // Join all handles, collecting the outcome of every collection
+    for result in futures::future::join_all(handles).await {
+        match result {
+            Ok(outcome) => summary.push(outcome),
+            Err(e) => {
+                log::error!("Thread error: {}", e);
+                summary.push(Outcome { error: Some(e.to_string()), ..Outcome::new(String::new(), String::new(), "failed") });
+            }
+        };
+    }
+
+    if !resuming {
+        progress.finish();
+    };
+
+    // Make sure the last checkpoints are on disk, without giving up on the views and summary when they cannot be
+    if let Err(e) = state.lock().unwrap().save() {
+        log::error!("Failed to save state: {}", e);
+    };
+
+    // Recreate views once their collections are in place
+    if !resuming {
+        for (source, view) in views {
+            let name = view.get_str("name").unwrap_or_default().to_string();
+            let (db, coll) = mapping.map(&source.db, &name);
+            let mut outcome = Outcome::new(format!("{}.{}", source.db, name), format!("{}.{}", db, coll), "done");
+            if let Err(e) = transfer_view(source, destination_db.clone(), view, mapping.clone(), opts.is_present("drop")).await {
+                log::error!("View error: {}", e);
+                outcome.status = "failed".to_string();
+                outcome.error = Some(e.to_string());
+            };
+            summary.push(outcome);
+        };
+    };
+
+    if !resuming {
+        summary.print(opts.value_of("summary").unwrap_or("table"));
+    };
+
+    // Replay changes made during and after the initial copy until stopped
+    if opts.is_present("follow") {
+        follow(source_db, destination_db, opts, targets, mapping, follow_start, state, limiter).await?;
+    };
+
+    // Exit non-zero when any collection failed to copy, or did not validate
+    if summary.exit_code() != 0 {
+        log::error!("{} collections failed, {} did not validate", summary.failed(), summary.invalid());
+        std::process::exit(summary.exit_code());
+    };
 
     Ok(())
 }
+
+// Ask a yes or no question on the terminal
+fn confirm(question: &str) -> BoxResult<bool> {
+    print!("{} Type yes to continue: ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+// Get a destination handle for a source namespace, with the renamed db and collection it maps to
+fn destination_for(destination_db: &DB, mapping: &Mapping, db: &str, collection: &str) -> (DB, Option<String>) {
+    let (dest_db, dest_coll) = mapping.map(db, collection);
+
+    let renamedb = match dest_db != db {
+        true => Some(dest_db.as_str()),
+        false => None
+    };
+
+    let rename_coll = match dest_coll != collection {
+        true => Some(dest_coll.clone()),
+        false => None
+    };
+
+    (destination_db.database(db, renamedb), rename_coll)
+}
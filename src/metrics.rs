@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+pub const DOCS_READ: &str = "mongodb_stream_docs_read_total";
+pub const DOCS_WRITTEN: &str = "mongodb_stream_docs_written_total";
+pub const BYTES_WRITTEN: &str = "mongodb_stream_bytes_written_total";
+pub const WRITE_ERRORS: &str = "mongodb_stream_write_errors_total";
+pub const WRITE_SECONDS: &str = "mongodb_stream_write_seconds";
+pub const BATCHES_IN_FLIGHT: &str = "mongodb_stream_batches_in_flight";
+pub const CHANGES_APPLIED: &str = "mongodb_stream_changes_applied_total";
+pub const CHANGE_STREAM_LAG: &str = "mongodb_stream_change_stream_lag_seconds";
+const PERMITS: &str = "mongodb_stream_collection_permits";
+const PERMITS_USED: &str = "mongodb_stream_collection_permits_used";
+
+// Every metric with its type and help text, in the order they are served
+const METRICS: [(&str, &str, &str); 10] = [
+    (DOCS_READ, "counter", "Docs read from the source"),
+    (DOCS_WRITTEN, "counter", "Docs written to the destination"),
+    (BYTES_WRITTEN, "counter", "Encoded BSON bytes of the docs written to the destination"),
+    (WRITE_ERRORS, "counter", "Write errors by server error code, 0 when there is none, including errors that were retried"),
+    (WRITE_SECONDS, "histogram", "Time taken by each insert or upsert request"),
+    (BATCHES_IN_FLIGHT, "gauge", "Batches being written, including time spent waiting to retry"),
+    (CHANGES_APPLIED, "counter", "Change stream events applied to the destination"),
+    (CHANGE_STREAM_LAG, "gauge", "Seconds between a change on the source and it being applied to the destination"),
+    (PERMITS, "gauge", "Collections that can be copied at once"),
+    (PERMITS_USED, "gauge", "Collections being copied")
+];
+
+// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 13] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+// Recording is skipped unless the metrics are served
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Process wide registry, so that metrics can be recorded anywhere in the same way as logs
+static REGISTRY: Registry = Registry {
+    values: Mutex::new(BTreeMap::new()),
+    histograms: Mutex::new(BTreeMap::new()),
+    semaphore: Mutex::new(None)
+};
+
+// Series are keyed by metric name and rendered labels
+struct Registry {
+    values: Mutex<BTreeMap<(&'static str, String), f64>>,
+    histograms: Mutex<BTreeMap<(&'static str, String), Histogram>>,
+    semaphore: Mutex<Option<(Arc<Semaphore>, usize)>>
+}
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Add to a counter, or to a gauge with a negative value to take away
+pub fn add(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    if !enabled() {
+        return
+    };
+    *REGISTRY.values.lock().unwrap().entry((name, render_labels(labels))).or_insert(0.0) += value;
+}
+
+pub fn set(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    if !enabled() {
+        return
+    };
+    REGISTRY.values.lock().unwrap().insert((name, render_labels(labels)), value);
+}
+
+pub fn observe(name: &'static str, labels: &[(&str, &str)], value: Duration) {
+    if !enabled() {
+        return
+    };
+    let seconds = value.as_secs_f64();
+    let mut histograms = REGISTRY.histograms.lock().unwrap();
+    let histogram = histograms.entry((name, render_labels(labels))).or_default();
+    for (count, bound) in histogram.counts.iter_mut().zip(BUCKETS.iter()) {
+        if seconds <= *bound {
+            *count += 1;
+        };
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+}
+
+// Report how many permits of the collection semaphore are in use whenever metrics are scraped
+pub fn track_semaphore(semaphore: Arc<Semaphore>, permits: usize) {
+    *REGISTRY.semaphore.lock().unwrap() = Some((semaphore, permits));
+}
+
+// Seconds since a change happened on the source, given its cluster time
+pub fn lag(seconds: u32) -> f64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+    (now - seconds as f64).max(0.0)
+}
+
+// Serve the metrics in the Prometheus text format on /metrics
+pub async fn serve(addr: &str) -> BoxResult<()> {
+    let listener = TcpListener::bind(addr).await?;
+    ENABLED.store(true, Ordering::Relaxed);
+    log::info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::error!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                // Only the request line matters, scrapes have no body
+                let mut request = [0u8; 1024];
+                let read = socket.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..read]);
+                let response = match request.split_whitespace().nth(1) {
+                    Some("/metrics") => {
+                        let body = render();
+                        format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+                    },
+                    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+                };
+                if let Err(e) = socket.write_all(response.as_bytes()).await {
+                    log::debug!("Failed to send metrics to {}: {}", peer, e);
+                };
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn render() -> String {
+    let mut values = REGISTRY.values.lock().unwrap().clone();
+    if let Some((semaphore, permits)) = REGISTRY.semaphore.lock().unwrap().as_ref() {
+        values.insert((PERMITS, String::new()), *permits as f64);
+        values.insert((PERMITS_USED, String::new()), permits.saturating_sub(semaphore.available_permits()) as f64);
+    };
+    let histograms = REGISTRY.histograms.lock().unwrap();
+
+    let mut out = String::new();
+    for (name, kind, help) in METRICS.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+        for ((_, labels), value) in values.range((*name, String::new())..).take_while(|((n, _), _)| n == name) {
+            let _ = writeln!(out, "{}{} {}", name, braces(labels), value);
+        }
+
+        for ((_, labels), histogram) in histograms.range((*name, String::new())..).take_while(|((n, _), _)| n == name) {
+            let separator = if labels.is_empty() { "" } else { "," };
+            for (count, bound) in histogram.counts.iter().zip(BUCKETS.iter()) {
+                let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, count);
+            }
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, histogram.count);
+            let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", name, braces(labels), histogram.count);
+        }
+    }
+    out
+}
+
+// Labels as name="value" pairs, with the value escaped as the text format requires
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels.iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn braces(labels: &str) -> String {
+    match labels.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", labels)
+    }
+}
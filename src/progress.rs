@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Progress of the whole job, fed by the counter of every collection being copied
+pub type Progress = Arc<JobProgress>;
+
+// How often overall progress is logged
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Weight of the latest throughput sample in the smoothed rate
+const SMOOTHING: f64 = 0.3;
+
+#[derive(Debug)]
+pub struct JobProgress {
+    job: Mutex<Job>
+}
+
+#[derive(Debug)]
+struct Job {
+    // Docs to copy and their average size, per source namespace
+    collections: HashMap<String, (f64, f64)>,
+    docs: f64,
+    bytes: f64,
+    started: Instant,
+    sampled: Instant,
+    sampled_docs: f64,
+    rate: Option<f64>
+}
+
+impl Job {
+    fn total_docs(&self) -> f64 {
+        self.collections.values().map(|(docs, _)| docs).sum()
+    }
+
+    fn total_bytes(&self) -> f64 {
+        self.collections.values().map(|(docs, size)| docs * size).sum()
+    }
+}
+
+impl JobProgress {
+    pub fn new() -> Progress {
+        let now = Instant::now();
+        Arc::new(JobProgress {
+            job: Mutex::new(Job {
+                collections: HashMap::new(),
+                docs: 0.0,
+                bytes: 0.0,
+                started: now,
+                sampled: now,
+                sampled_docs: 0.0,
+                rate: None
+            })
+        })
+    }
+
+    // Queue a collection, with the doc count and data size estimated by the server
+    pub fn add(&self, namespace: &str, docs: f64, bytes: f64) {
+        let size = match docs > 0.0 {
+            true => bytes / docs,
+            false => 0.0
+        };
+        self.job.lock().unwrap().collections.insert(namespace.to_string(), (docs, size));
+    }
+
+    // Replace the estimate once the exact number of docs to copy is known, after --query, --resume or --continue
+    pub fn set_docs(&self, namespace: &str, docs: f64) {
+        let mut job = self.job.lock().unwrap();
+        let entry = job.collections.entry(namespace.to_string()).or_insert((0.0, 0.0));
+        entry.0 = docs;
+    }
+
+    // Count docs copied, logging overall progress at most once per interval
+    pub fn advance(&self, namespace: &str, docs: f64) {
+        let mut job = self.job.lock().unwrap();
+        let size = job.collections.get(namespace).map(|(_, size)| *size).unwrap_or(0.0);
+        job.docs += docs;
+        job.bytes += docs * size;
+
+        let elapsed = job.sampled.elapsed();
+        if elapsed < REPORT_INTERVAL {
+            return
+        };
+
+        // Exponentially weighted throughput, so that the ETA does not swing with every batch
+        let sample = (job.docs - job.sampled_docs) / elapsed.as_secs_f64();
+        job.rate = Some(match job.rate {
+            Some(rate) => SMOOTHING * sample + (1.0 - SMOOTHING) * rate,
+            None => sample
+        });
+        job.sampled = Instant::now();
+        job.sampled_docs = job.docs;
+        report(&job);
+    }
+
+    pub fn finish(&self) {
+        let job = self.job.lock().unwrap();
+        let elapsed = job.started.elapsed().as_secs_f64();
+        let rate = match elapsed > 0.0 {
+            true => job.docs / elapsed,
+            false => 0.0
+        };
+        log::info!(phase = "copy", count = job.docs as u64, rate; "Job: Copied {} docs in {}, {:.2}/s", job.docs, duration(elapsed), rate);
+    }
+}
+
+fn report(job: &Job) {
+    let total = job.total_docs();
+    let total_bytes = job.total_bytes();
+    let rate = job.rate.unwrap_or(0.0);
+
+    // Nothing left to copy counts as done
+    let percent = match total > 0.0 {
+        true => (job.docs / total * 100.0).min(100.0),
+        false => 100.0
+    };
+
+    let remaining = (total - job.docs).max(0.0);
+    let eta = match rate > 0.0 {
+        true => Some(remaining / rate),
+        false => None
+    };
+
+    log::info!(phase = "copy", count = job.docs as u64, total = total as u64, bytes = job.bytes as u64, total_bytes = total_bytes as u64, percent, rate, eta_seconds = eta.map(|e| e as u64);
+        "Job: {:.2}%, {}/{} docs, {:.2}/s, ETA {}", percent, job.docs, total, rate, eta.map(duration).unwrap_or_else(|| "unknown".to_string()));
+}
+
+// Seconds as hours, minutes and seconds
+fn duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!("{}h{:02}m{:02}s", seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}
@@ -0,0 +1,353 @@
+use bson::{doc, Bson, Document};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::db::has_decimal128;
+
+type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+// State shared between all collection tasks
+pub type SharedState = Arc<Mutex<State>>;
+
+// How often checkpoints are written while a collection is copying
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Local state that survives restarts, stored as extended json
+#[derive(Clone, Debug)]
+pub struct State {
+    pub path: String,
+    pub tokens: Document,
+    // Destination each resume token was saved for, so that a token is never used to follow onto another one
+    pub destinations: Document,
+    pub collections: Document,
+    pub saved: Instant
+}
+
+impl State {
+    pub fn load(path: &str) -> BoxResult<Self> {
+        let mut state = State {
+            path: path.to_owned(),
+            tokens: Document::new(),
+            destinations: Document::new(),
+            collections: Document::new(),
+            saved: Instant::now()
+        };
+
+        if !Path::new(path).exists() {
+            log::debug!("State file {} does not exist yet", path);
+            return Ok(state)
+        };
+
+        let contents = fs::read_to_string(path)?;
+        let value: serde_json::Value = serde_json::from_str(&contents)?;
+        let doc = match Bson::try_from(value)? {
+            Bson::Document(doc) => doc,
+            _ => return Err(format!("State file {} does not contain a json object", path).into())
+        };
+
+        if let Ok(tokens) = doc.get_document("tokens") {
+            state.tokens = tokens.clone();
+        };
+
+        if let Ok(destinations) = doc.get_document("destinations") {
+            state.destinations = destinations.clone();
+        };
+
+        if let Ok(collections) = doc.get_document("collections") {
+            state.collections = collections.clone();
+        };
+
+        log::info!("Loaded state from {}", path);
+        Ok(state)
+    }
+
+    pub fn save(&mut self) -> BoxResult<()> {
+        let doc = Bson::Document(doc! {
+            "tokens": self.tokens.clone(),
+            "destinations": self.destinations.clone(),
+            "collections": self.collections.clone()
+        });
+
+        // Converting a Decimal128 to extended json panics, which would poison the state lock for every task
+        if has_decimal128(&doc) {
+            return Err(format!("State for {} contains a Decimal128 value, which cannot be saved", self.path).into())
+        };
+
+        let contents = serde_json::to_string_pretty(&doc.into_canonical_extjson())?;
+
+        // Write to a temp file first, so that a crash never leaves a truncated state file behind
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &self.path)?;
+        self.saved = Instant::now();
+
+        log::debug!("Saved state to {}", self.path);
+        Ok(())
+    }
+
+    pub fn resume_token(&self, namespace: &str) -> Option<Document> {
+        self.tokens.get_document(namespace).ok().cloned()
+    }
+
+    pub fn set_resume_token(&mut self, namespace: &str, token: Document) {
+        self.tokens.insert(namespace, token);
+    }
+
+    pub fn clear_resume_token(&mut self, namespace: &str) {
+        self.tokens.remove(namespace);
+        self.destinations.remove(namespace);
+    }
+
+    pub fn destination(&self, namespace: &str) -> Option<Document> {
+        self.destinations.get_document(namespace).ok().cloned()
+    }
+
+    pub fn set_destination(&mut self, namespace: &str, destination: Document) {
+        self.destinations.insert(namespace, destination);
+    }
+}
+
+// Batches written by concurrent insert tasks, which can complete out of order
+#[derive(Clone, Debug, Default)]
+pub struct Batches {
+    pub next: u64,
+    pub written: BTreeMap<u64, (Option<Bson>, f64)>,
+    pub count: f64,
+    // Earliest batch that failed, past which the last _id can never move
+    pub failed: Option<u64>
+}
+
+// Progress of a single collection within the state file, or of one _id range of it
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub state: SharedState,
+    pub namespace: String,
+    pub partition: Option<usize>,
+    pub batches: Arc<Mutex<Batches>>
+}
+
+impl Checkpoint {
+    pub fn new(state: &SharedState, db: &str, collection: &str) -> Self {
+        Checkpoint {
+            state: Arc::clone(state),
+            namespace: format!("{}.{}", db, collection),
+            partition: None,
+            batches: Arc::new(Mutex::new(Batches::default()))
+        }
+    }
+
+    // Checkpoint of one _id range, stored under the collection's partitions
+    pub fn partition(&self, partition: usize) -> Self {
+        Checkpoint {
+            state: Arc::clone(&self.state),
+            namespace: self.namespace.clone(),
+            partition: Some(partition),
+            batches: Arc::new(Mutex::new(Batches::default()))
+        }
+    }
+
+    pub fn get(&self) -> Option<Document> {
+        let state = self.state.lock().unwrap();
+        let collection = state.collections.get_document(&self.namespace).ok()?;
+        match self.partition {
+            Some(partition) => collection.get_document("partitions").ok()?.get_document(&partition.to_string()).ok().cloned(),
+            None => Some(collection.clone())
+        }
+    }
+
+    pub fn status(&self) -> Option<String> {
+        self.get().and_then(|c| c.get_str("status").ok().map(String::from))
+    }
+
+    // Pick up right after the last _id known to be written to the destination, along with every doc before it,
+    // carrying over the count of docs already written
+    pub fn resume(&self) -> Option<Bson> {
+        let checkpoint = self.get()?;
+        if let Ok(count) = checkpoint.get_f64("count") {
+            self.batches.lock().unwrap().count = count;
+        };
+        checkpoint.get("last_id").map(decode_id)
+    }
+
+    // _id boundaries the collection was split at, so that a resumed run uses the same ranges
+    pub fn boundaries(&self) -> Option<Vec<Bson>> {
+        self.get().and_then(|c| c.get_array("boundaries").ok().map(|b| b.iter().map(decode_id).collect()))
+    }
+
+    pub fn set_boundaries(&self, boundaries: Vec<Bson>) {
+        let boundaries: Vec<Bson> = boundaries.iter().map(encode_id).collect();
+        self.update(doc! { "boundaries": boundaries, "partitions": {} }, true);
+    }
+
+    // Start the collection over, forgetting any previous progress
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.collections.insert(&self.namespace, doc! { "status": "pending" });
+    }
+
+    pub fn set_status(&self, status: &str) {
+        self.update(doc! { "status": status }, true);
+    }
+
+    pub fn fail(&self, error: &str) {
+        self.update(doc! { "status": "failed", "error": error }, true);
+    }
+
+    // Record a finished batch, numbered in cursor order, and move the last confirmed _id past
+    // every batch that has been written along with all batches before it
+    pub fn written(&self, batch: u64, last_id: Option<Bson>, count: f64, ok: bool) {
+        let mut batches = self.batches.lock().unwrap();
+        if batches.failed.is_some_and(|failed| batch >= failed) {
+            return
+        };
+
+        // Batches after a failed one are never confirmed, so they are not kept
+        if !ok {
+            batches.failed = Some(batch);
+            batches.written.retain(|b, _| *b < batch);
+            return
+        };
+        batches.written.insert(batch, (last_id, count));
+
+        let mut confirmed = None;
+        loop {
+            let next = batches.next;
+            match batches.written.remove(&next) {
+                Some((last_id, count)) => {
+                    batches.count += count;
+                    batches.next += 1;
+                    if last_id.is_some() {
+                        confirmed = last_id;
+                    };
+                },
+                _ => break
+            }
+        }
+
+        if let Some(last_id) = confirmed {
+            let count = batches.count;
+            drop(batches);
+            self.update(doc! { "last_id": encode_id(&last_id), "count": count }, false);
+        };
+    }
+
+    // Whether any batch failed to be written
+    pub fn failed(&self) -> bool {
+        self.batches.lock().unwrap().failed.is_some()
+    }
+
+    // Merge fields into the checkpoint, saving right away or at most once per interval
+    fn update(&self, fields: Document, force: bool) {
+        let mut state = self.state.lock().unwrap();
+        let mut collection = state.collections.get_document(&self.namespace).cloned().unwrap_or_default();
+
+        match self.partition {
+            Some(partition) => {
+                let mut partitions = collection.get_document("partitions").cloned().unwrap_or_default();
+                let mut checkpoint = partitions.get_document(&partition.to_string()).cloned().unwrap_or_default();
+                checkpoint.extend(fields);
+                partitions.insert(partition.to_string(), checkpoint);
+                collection.insert("partitions", partitions);
+            },
+            None => {
+                // A new status clears the error of an earlier failure
+                if fields.contains_key("status") && !fields.contains_key("error") {
+                    collection.remove("error");
+                };
+                collection.extend(fields);
+            }
+        };
+
+        state.collections.insert(&self.namespace, collection);
+
+        if force || state.saved.elapsed() >= CHECKPOINT_INTERVAL {
+            if let Err(e) = state.save() {
+                log::error!("{}: Failed to save checkpoint: {}", self.namespace, e);
+            };
+        };
+    }
+}
+
+// _ids are kept as base64 of their BSON encoding, so that every type survives the extended json state file
+fn encode_id(id: &Bson) -> Bson {
+    let mut bytes = Vec::new();
+    match doc! { "_id": id.clone() }.to_writer(&mut bytes) {
+        Ok(_) => Bson::String(base64::encode(bytes)),
+        Err(_) => id.clone()
+    }
+}
+
+// Decode an _id saved by encode_id, or written as plain extended json by an older version
+fn decode_id(value: &Bson) -> Bson {
+    if let Bson::String(encoded) = value {
+        let decoded = base64::decode(encoded).ok()
+            .and_then(|bytes| Document::from_reader(&mut bytes.as_slice()).ok())
+            .and_then(|doc| doc.get("_id").cloned());
+        if let Some(id) = decoded {
+            return id
+        };
+    };
+    value.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bson is built without Decimal128 constructors, so read one from its encoding
+    fn decimal128() -> Bson {
+        let mut bytes = vec![26, 0, 0, 0, 0x13, b'_', b'i', b'd', 0];
+        bytes.extend_from_slice(&[1; 16]);
+        bytes.push(0);
+        Document::from_reader(&mut bytes.as_slice()).unwrap().get("_id").cloned().unwrap()
+    }
+
+    #[test]
+    fn ids_survive_encoding() {
+        for id in [decimal128(), Bson::ObjectId(bson::oid::ObjectId::new()), Bson::String("abc".to_string()), Bson::Int64(7)] {
+            assert_eq!(decode_id(&encode_id(&id)), id);
+        }
+    }
+
+    #[test]
+    fn plain_ids_from_older_state_files_are_kept() {
+        assert_eq!(decode_id(&Bson::Int32(5)), Bson::Int32(5));
+        assert_eq!(decode_id(&Bson::String("not base64!".to_string())), Bson::String("not base64!".to_string()));
+    }
+
+    #[test]
+    fn checkpoint_with_decimal128_id_saves() {
+        let path = std::env::temp_dir().join(format!("mongodb-stream-rs-test-{}.state", std::process::id()));
+        let state: SharedState = Arc::new(Mutex::new(State::load(path.to_str().unwrap()).unwrap()));
+        let checkpoint = Checkpoint::new(&state, "db", "coll");
+        checkpoint.written(0, Some(decimal128()), 1.0, true);
+        state.lock().unwrap().save().unwrap();
+
+        let loaded: SharedState = Arc::new(Mutex::new(State::load(path.to_str().unwrap()).unwrap()));
+        assert_eq!(Checkpoint::new(&loaded, "db", "coll").resume(), Some(decimal128()));
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn batches_after_a_failure_are_dropped() {
+        let state: SharedState = Arc::new(Mutex::new(State::load("/nonexistent/mongodb-stream-rs.state").unwrap()));
+        let checkpoint = Checkpoint::new(&state, "db", "coll");
+        checkpoint.written(2, Some(Bson::Int32(2)), 1.0, true);
+        checkpoint.written(1, None, 1.0, false);
+        for batch in 3..100 {
+            checkpoint.written(batch, Some(Bson::Int32(batch as i32)), 1.0, true);
+        }
+        checkpoint.written(0, Some(Bson::Int32(0)), 1.0, true);
+
+        let batches = checkpoint.batches.lock().unwrap();
+        assert!(batches.written.is_empty());
+        assert_eq!(batches.next, 1);
+        assert_eq!(batches.failed, Some(1));
+        assert_eq!(batches.count, 1.0);
+    }
+}
@@ -0,0 +1,311 @@
+use bson::{doc, Bson, Document, Timestamp};
+use clap::ArgMatches;
+use futures::StreamExt;
+use mongodb::error::ErrorKind;
+use mongodb::options::{AggregateOptions, ClientOptions, ReadConcern, ReplaceOptions};
+use mongodb::Cursor;
+use std::error;
+use std::time::{Duration, Instant};
+
+use crate::db::DB;
+use crate::filter::Mapping;
+use crate::limit::Limiter;
+use crate::metrics;
+use crate::state::{SharedState, State};
+
+type BoxResult<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+// Server error codes after which a change stream can be reopened, as listed in the driver change stream spec
+const RESUMABLE_CODES: [i32; 17] = [
+    6, 7, 63, 89, 91, 133, 150, 189, 234, 262, 9001, 10107, 11600, 11602, 13388, 13435, 13436
+];
+
+// Server error codes meaning the resume point is no longer in the oplog
+const HISTORY_LOST_CODES: [i32; 3] = [
+    136,    // CappedPositionLost
+    280,    // ChangeStreamFatalError
+    286,    // ChangeStreamHistoryLost
+];
+
+// How often to persist the resume token while following
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Get the current operation time of the source, so that a change stream can be started before the initial copy
+pub async fn operation_time(source_db: &DB) -> BoxResult<Timestamp> {
+    let response = source_db.client.database(&source_db.db).run_command(doc! { "ping": 1 }, None).await?;
+
+    // Prefer operationTime, but fall back to the gossiped cluster time
+    if let Ok(ts) = response.get_timestamp("operationTime") {
+        return Ok(ts)
+    };
+
+    match response.get_document("$clusterTime").and_then(|d| d.get_timestamp("clusterTime")) {
+        Ok(ts) => Ok(ts),
+        Err(_) => Err("Source did not return an operation time, --follow requires a replicaset or sharded cluster".into())
+    }
+}
+
+// Hosts of the destination and the namespaces changes end up in, saved along with the resume token
+pub async fn destination(uri: &str, namespace: &str, targets: &[(String, String)], mapping: &Mapping) -> BoxResult<Document> {
+    let mut hosts: Vec<String> = ClientOptions::parse(uri).await?.hosts.iter().map(|h| h.to_string()).collect();
+    hosts.sort();
+
+    // A single collection maps to a collection, a db or the cluster to the dbs its collections are moved to
+    let mut namespaces: Vec<String> = match namespace.split_once('.') {
+        Some((db, coll)) => {
+            let (db, coll) = mapping.map(db, coll);
+            vec![format!("{}.{}", db, coll)]
+        },
+        None => targets.iter().map(|(db, coll)| mapping.map(db, coll).0).collect()
+    };
+    namespaces.sort();
+    namespaces.dedup();
+
+    Ok(doc! { "hosts": hosts, "namespaces": namespaces })
+}
+
+// Check if a saved resume token can still be used to resume the change stream
+pub async fn can_resume(source_db: &DB, opts: &ArgMatches<'_>, targets: &[(String, String)], resume_token: &Document) -> BoxResult<bool> {
+    match watch(source_db, opts.is_present("all_dbs"), targets, None, Some(resume_token)).await {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            if history_lost(&e) {
+                log::error!("{}: Resume token is no longer in the source oplog: {}", source_db.db, e);
+                Ok(false)
+            } else {
+                Err(Box::new(e))
+            }
+        }
+    }
+}
+
+// Resume tokens are saved per source db, per collection when only one is followed, or for the whole cluster
+pub fn namespace(db: &str, opts: &ArgMatches<'_>) -> String {
+    if opts.is_present("all_dbs") {
+        return "cluster".to_string()
+    };
+
+    match opts.value_of("collection") {
+        Some(coll) => format!("{}.{}", db, coll),
+        None => db.to_string()
+    }
+}
+
+// Replay changes on the (db, collection) targets, until stopped
+#[allow(clippy::too_many_arguments)]
+pub async fn follow(source_db: DB, destination_db: DB, opts: ArgMatches<'_>, targets: Vec<(String, String)>, mapping: Mapping, start_at: Option<Timestamp>, state: SharedState, limiter: Limiter) -> BoxResult<()> {
+    let namespace = namespace(&source_db.db, &opts);
+    let cluster = opts.is_present("all_dbs");
+
+    // Stop following when we get a ctrl-c
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    // Resume token of the last change applied to the destination
+    let mut resume_token: Option<Document> = state.lock().unwrap().resume_token(&namespace);
+
+    // Count of changes applied
+    let mut applied: u64 = 0;
+
+    // Time the resume token was last saved
+    let mut saved = Instant::now();
+
+    match resume_token {
+        Some(_) => log::info!("{}: Resuming changes on {} collections from saved token", namespace, targets.len()),
+        None => log::info!("{}: Following changes on {} collections", namespace, targets.len())
+    };
+
+    loop {
+        // Start at the operation time captured before the initial copy, or resume after the last applied change
+        let mut cursor = match watch(&source_db, cluster, &targets, start_at, resume_token.as_ref()).await {
+            Ok(cursor) => cursor,
+            Err(e) => {
+                if is_resumable(&e) {
+                    log::error!("{}: Error opening change stream, retrying: {}", namespace, e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                } else {
+                    return Err(lost(&mut state.lock().unwrap(), &namespace, e))
+                }
+            }
+        };
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => {
+                    state.lock().unwrap().save()?;
+                    log::info!(namespace = namespace.as_str(), phase = "follow", applied; "{}: Stopping change stream, applied {} changes", namespace, applied);
+                    return Ok(())
+                }
+                event = cursor.next() => match event {
+                    Some(Ok(event)) => {
+                        if !apply_change(&destination_db, &event, &mapping, &limiter).await? {
+                            let mut state = state.lock().unwrap();
+                            state.clear_resume_token(&namespace);
+                            state.save()?;
+                            log::info!(namespace = namespace.as_str(), phase = "follow", applied; "{}: Change stream was invalidated, applied {} changes", namespace, applied);
+                            return Ok(())
+                        };
+
+                        resume_token = event.get_document("_id").ok().cloned();
+
+                        metrics::add(metrics::CHANGES_APPLIED, &[("namespace", &namespace)], 1.0);
+                        if let Ok(cluster_time) = event.get_timestamp("clusterTime") {
+                            metrics::set(metrics::CHANGE_STREAM_LAG, &[("namespace", &namespace)], metrics::lag(cluster_time.time));
+                        };
+
+                        // Persist the token, so that a restart picks up where we left off
+                        if let Some(token) = &resume_token {
+                            let mut state = state.lock().unwrap();
+                            state.set_resume_token(&namespace, token.clone());
+                            if saved.elapsed() >= SAVE_INTERVAL {
+                                state.save()?;
+                                saved = Instant::now();
+                            };
+                        };
+
+                        applied += 1;
+                        if applied.is_multiple_of(1000) {
+                            log::info!(namespace = namespace.as_str(), phase = "follow", applied; "{}: Applied {} changes", namespace, applied);
+                        };
+                    },
+                    Some(Err(e)) => {
+                        if is_resumable(&e) {
+                            log::error!("{}: Caught error on change stream, resuming: {}", namespace, e);
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            break;
+                        } else {
+                            state.lock().unwrap().save()?;
+                            return Err(lost(&mut state.lock().unwrap(), &namespace, e))
+                        }
+                    },
+                    None => {
+                        log::info!("{}: Change stream closed, resuming", namespace);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Open a change stream on the source db, or the whole cluster, limited to the collections being copied
+async fn watch(source_db: &DB, cluster: bool, targets: &[(String, String)], start_at: Option<Timestamp>, resume_token: Option<&Document>) -> mongodb::error::Result<Cursor> {
+    let mut change_stream = doc! { "fullDocument": "updateLookup" };
+    if cluster {
+        change_stream.insert("allChangesForCluster", true);
+    };
+
+    match (resume_token, start_at) {
+        (Some(token), _) => change_stream.insert("resumeAfter", token.clone()),
+        (None, Some(ts)) => change_stream.insert("startAtOperationTime", ts),
+        (None, None) => None
+    };
+
+    // Match on both db and collection, since a cluster stream sees every database
+    let namespaces: Vec<Document> = targets.iter()
+        .map(|(db, coll)| doc! { "ns.db": db, "ns.coll": coll })
+        .collect();
+
+    // An empty $or is rejected by the server, so match nothing instead
+    let filter = match namespaces.is_empty() {
+        true => doc! { "_id": { "$exists": false } },
+        false => doc! { "$or": namespaces }
+    };
+
+    let pipeline = vec![
+        doc! { "$changeStream": change_stream },
+        doc! { "$match": filter }
+    ];
+
+    let aggregate_options = AggregateOptions::builder()
+        .read_concern(ReadConcern::majority())
+        .build();
+
+    // Cluster wide change streams have to be opened on the admin db
+    let db = match cluster {
+        true => "admin",
+        false => &source_db.db
+    };
+
+    source_db.client.database(db).aggregate(pipeline, aggregate_options).await
+}
+
+// Drop a resume token that can no longer be used, and explain how to recover
+fn lost(state: &mut State, namespace: &str, e: mongodb::error::Error) -> Box<dyn error::Error + Send + Sync> {
+    if history_lost(&e) {
+        state.clear_resume_token(namespace);
+        if let Err(err) = state.save() {
+            log::error!("{}: Failed to clear resume token: {}", namespace, err);
+        };
+        format!("{}: Change stream can no longer be resumed, rerun with --follow to fully resync: {}", namespace, e).into()
+    } else {
+        Box::new(e)
+    }
+}
+
+// Replay a single change event onto the destination, returns false when the stream has been invalidated
+async fn apply_change(destination_db: &DB, event: &Document, mapping: &Mapping, limiter: &Limiter) -> BoxResult<bool> {
+    let operation = event.get_str("operationType")?;
+
+    // Events such as invalidate and dropDatabase do not carry a collection
+    let ns = event.get_document("ns").ok();
+    let source_db = ns.and_then(|ns| ns.get_str("db").ok()).unwrap_or(&destination_db.db);
+    let source_collection = match ns.and_then(|ns| ns.get_str("coll").ok()) {
+        Some(coll) => coll,
+        None => {
+            log::warn!("{}: Got {} event", source_db, operation);
+            return Ok(operation != "invalidate")
+        }
+    };
+
+    // Get destination namespace
+    let (db, collection) = mapping.map(source_db, source_collection);
+
+    let collection_handle = destination_db.client.database(&db).collection(&collection);
+
+    let document_key = event.get_document("documentKey").ok().cloned();
+
+    match (operation, document_key) {
+        ("insert", Some(key)) | ("replace", Some(key)) | ("update", Some(key)) => {
+            match event.get("fullDocument") {
+                Some(Bson::Document(full_document)) => {
+                    limiter.acquire_doc(full_document).await;
+                    let options = ReplaceOptions::builder().upsert(true).build();
+                    collection_handle.replace_one(key, full_document.clone(), options).await?;
+                    log::debug!("{}.{}: Applied {}", db, collection, operation);
+                },
+                _ => {
+                    // The doc was deleted before the update could be looked up, the delete event will follow
+                    log::debug!("{}.{}: Skipping {} without a full document", db, collection, operation);
+                }
+            }
+        },
+        ("delete", Some(key)) => {
+            limiter.acquire(1, 0).await;
+            collection_handle.delete_one(key, None).await?;
+            log::debug!("{}.{}: Applied delete", db, collection);
+        },
+        ("invalidate", _) => return Ok(false),
+        (operation, _) => {
+            log::warn!("{}.{}: Ignoring {} event", db, collection, operation);
+        }
+    };
+
+    Ok(true)
+}
+
+fn history_lost(e: &mongodb::error::Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::CommandError(err) => HISTORY_LOST_CODES.contains(&err.code),
+        _ => false
+    }
+}
+
+fn is_resumable(e: &mongodb::error::Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::CommandError(err) => RESUMABLE_CODES.contains(&err.code) || e.contains_label("ResumableChangeStreamError"),
+        ErrorKind::Io(_) => true,
+        _ => e.contains_label("NetworkError")
+    }
+}
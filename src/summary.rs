@@ -0,0 +1,115 @@
+use serde_json::json;
+use std::time::Duration;
+
+// What happened to one collection or view during a run
+#[derive(Clone, Debug, Default)]
+pub struct Outcome {
+    pub source: String,
+    pub destination: String,
+    pub status: String,
+    pub read: u64,
+    pub written: u64,
+    pub duplicates: u64,
+    pub failed: u64,
+    pub duration: Duration,
+    pub validation: Option<String>,
+    pub error: Option<String>
+}
+
+impl Outcome {
+    pub fn new(source: String, destination: String, status: &str) -> Self {
+        Outcome {
+            source,
+            destination,
+            status: status.to_string(),
+            ..Outcome::default()
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.status == "failed"
+    }
+
+    fn invalid(&self) -> bool {
+        matches!(self.validation.as_deref(), Some(v) if v != "valid")
+    }
+}
+
+// Exit codes, so that scripts can tell failed copies from copies that did not validate
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_INVALID: i32 = 2;
+
+// Per collection outcomes of a run
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub outcomes: Vec<Outcome>
+}
+
+impl Summary {
+    pub fn push(&mut self, outcome: Outcome) {
+        self.outcomes.push(outcome);
+    }
+
+    pub fn failed(&self) -> usize {
+        self.outcomes.iter().filter(|o| o.failed()).count()
+    }
+
+    pub fn invalid(&self) -> usize {
+        self.outcomes.iter().filter(|o| o.invalid()).count()
+    }
+
+    // 0 when every collection was copied and validated, otherwise the worst problem found
+    pub fn exit_code(&self) -> i32 {
+        match (self.failed(), self.invalid()) {
+            (0, 0) => 0,
+            (0, _) => EXIT_INVALID,
+            _ => EXIT_FAILED
+        }
+    }
+
+    // Print the summary on stdout as a table, or as a single json object
+    pub fn print(&self, format: &str) {
+        match format {
+            "json" => println!("{}", self.json()),
+            _ => print!("{}", self.table())
+        };
+    }
+
+    fn json(&self) -> serde_json::Value {
+        let collections: Vec<serde_json::Value> = self.outcomes.iter().map(|o| json!({
+            "source": o.source,
+            "destination": o.destination,
+            "status": o.status,
+            "read": o.read,
+            "written": o.written,
+            "duplicates": o.duplicates,
+            "failed": o.failed,
+            "seconds": o.duration.as_secs_f64(),
+            "validation": o.validation,
+            "error": o.error
+        })).collect();
+
+        json!({
+            "collections": collections,
+            "failed": self.failed(),
+            "invalid": self.invalid(),
+            "exit_code": self.exit_code()
+        })
+    }
+
+    fn table(&self) -> String {
+        let width = |name: &str, values: Vec<usize>| values.into_iter().max().unwrap_or(0).max(name.len());
+        let source = width("SOURCE", self.outcomes.iter().map(|o| o.source.len()).collect());
+        let destination = width("DESTINATION", self.outcomes.iter().map(|o| o.destination.len()).collect());
+
+        let mut table = format!("{:<s$}  {:<d$}  {:<8}  {:>10}  {:>10}  {:>10}  {:>10}  {:>9}  {}\n",
+            "SOURCE", "DESTINATION", "STATUS", "READ", "WRITTEN", "DUPLICATES", "FAILED", "SECONDS", "VALIDATION", s = source, d = destination);
+        for o in &self.outcomes {
+            table.push_str(&format!("{:<s$}  {:<d$}  {:<8}  {:>10}  {:>10}  {:>10}  {:>10}  {:>9.1}  {}\n",
+                o.source, o.destination, o.status, o.read, o.written, o.duplicates, o.failed, o.duration.as_secs_f64(),
+                o.validation.as_deref().unwrap_or("-"), s = source, d = destination));
+        }
+        table.push_str(&format!("{} collections, {} failed, {} did not validate\n", self.outcomes.len(), self.failed(), self.invalid()));
+        table
+    }
+}